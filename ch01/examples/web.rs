@@ -0,0 +1,242 @@
+// A tiny HTTP front end for the ch01 API, mostly to show how the library functions fit together.
+// Run it with `cargo run -p ch01 --example web`, then i.e.
+//
+// curl -X POST localhost:8000/articles -d '{"user": "username", "title": "A title", "link": "http://google.com"}'
+// curl -X POST localhost:8000/articles/1/vote -d '{"user": "other_user"}'
+// curl localhost:8000/articles?page=1
+use std::{error::Error, io::Read};
+
+use ch01::{article_vote, articles_to_json, get_articles, post_article_full, AppError};
+use redis::ConnectionLike;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let client = redis::Client::open("redis://127.0.0.1")?;
+    let server = Server::http("127.0.0.1:8000")?;
+    println!("Listening on http://127.0.0.1:8000");
+    serve(&server, &client)
+}
+
+// Handles requests until the server is unblocked
+fn serve(server: &Server, client: &redis::Client) -> Result<(), Box<dyn Error>> {
+    let mut conn = client.get_connection()?;
+    for mut request in server.incoming_requests() {
+        let (status, body) = match handle(&mut conn, &mut request) {
+            Ok(reply) => reply,
+            Err(HttpError(status, message)) => (status, json!({ "error": message })),
+        };
+        let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+            .expect("Content-Type should be a valid header");
+        let response = Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(content_type);
+        // A client that hung up early shouldn't take the whole server down with it
+        if let Err(e) = request.respond(response) {
+            eprintln!("Couldn't respond to a request: {e}");
+        }
+    }
+    Ok(())
+}
+
+// An HTTP status code along with a message explaining it
+struct HttpError(u16, String);
+
+impl HttpError {
+    fn bad_request<E: ToString>(e: E) -> HttpError {
+        HttpError(400, e.to_string())
+    }
+
+    fn internal<E: ToString>(e: E) -> HttpError {
+        HttpError(500, e.to_string())
+    }
+
+    // Posts the library refused as bad input (i.e. for a bad link) are the client's fault, anything
+    // else, including an id collision, is ours
+    fn from_post(e: Box<dyn Error>) -> HttpError {
+        match e.downcast_ref::<AppError>() {
+            Some(AppError::BadInput(_)) => HttpError::bad_request(e),
+            _ => HttpError::internal(e),
+        }
+    }
+
+    // Likewise for votes, except that voting on an article that doesn't exist is a 404, one that's
+    // closed for voting is a 403 and voting too often is a 429. Anything the library didn't refuse
+    // (i.e. Redis going away mid-vote) is still ours
+    fn from_vote(e: Box<dyn Error>) -> HttpError {
+        match e.downcast_ref::<AppError>() {
+            Some(AppError::NotFound(_)) => HttpError(404, e.to_string()),
+            Some(AppError::TooOld(_)) => HttpError(403, e.to_string()),
+            Some(AppError::RateLimited(_)) => HttpError(429, e.to_string()),
+            Some(AppError::BadInput(_)) => HttpError::bad_request(e),
+            _ => HttpError::internal(e),
+        }
+    }
+}
+
+fn handle(
+    conn: &mut impl ConnectionLike,
+    request: &mut Request,
+) -> Result<(u16, Value), HttpError> {
+    let method = request.method().clone();
+    let url = request.url().to_owned();
+    let (path, query) = url.split_once('?').unwrap_or((url.as_str(), ""));
+    let vote_id = path
+        .strip_prefix("/articles/")
+        .and_then(|rest| rest.strip_suffix("/vote"));
+
+    match (method, path, vote_id) {
+        (Method::Post, "/articles", _) => {
+            let body = read_json(request)?;
+            let article = post_article_full(
+                conn,
+                field(&body, "user")?,
+                field(&body, "title")?,
+                field(&body, "link")?,
+            )
+            .map_err(HttpError::from_post)?;
+            Ok((201, json!(article)))
+        }
+        (Method::Post, _, Some(id)) => {
+            let body = read_json(request)?;
+            let mut article = "article:".to_owned();
+            article.push_str(id);
+            let counted = article_vote(conn, field(&body, "user")?, &article)
+                .map_err(HttpError::from_vote)?;
+            Ok((200, json!({ "counted": counted })))
+        }
+        (Method::Get, "/articles", _) => {
+            let page = query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("page="))
+                .unwrap_or("1")
+                .parse::<isize>()
+                .ok()
+                .filter(|page| *page >= 1)
+                .ok_or_else(|| HttpError::bad_request("page should be a positive number"))?;
+            let articles = get_articles(conn, page, None).map_err(HttpError::internal)?;
+            Ok((200, articles_to_json(&articles)))
+        }
+        _ => Err(HttpError(404, format!("There's nothing at {path}"))),
+    }
+}
+
+fn read_json(request: &mut Request) -> Result<Value, HttpError> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .read_to_string(&mut body)
+        .map_err(HttpError::bad_request)?;
+    serde_json::from_str(&body).map_err(HttpError::bad_request)
+}
+
+fn field<'a>(body: &'a Value, name: &str) -> Result<&'a str, HttpError> {
+    body.get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| HttpError::bad_request(format!("The request is missing '{name}'")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        sync::Arc,
+        thread,
+    };
+
+    use redis::Commands;
+    use serde_json::Value;
+    use tiny_http::Server;
+
+    use crate::serve;
+
+    test_support::test_connections!();
+
+    // Sends a bare-bones HTTP/1.1 request and returns the status code and JSON body of the response
+    fn send(port: u16, method: &str, path: &str, body: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    // Execute`cargo test -p ch01 --example web -- --nocapture` to run this test
+    #[test]
+    fn test_endpoints() {
+        let (_redis, client) = test_client();
+        let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+        let port = server.server_addr().to_ip().unwrap().port();
+        let handle = {
+            let server = Arc::clone(&server);
+            let client = client.clone();
+            thread::spawn(move || serve(&server, &client).map_err(|e| e.to_string()))
+        };
+
+        let (status, posted) = send(
+            port,
+            "POST",
+            "/articles",
+            r#"{"user": "username", "title": "A title", "link": "http://google.com"}"#,
+        );
+        println!("Posting an article gave {status}: {posted}");
+        assert_eq!(status, 201);
+        let id = posted["id"].as_str().unwrap().to_owned();
+        assert_eq!(posted["votes"], 1);
+
+        let vote = format!("/articles/{id}/vote");
+        let (status, voted) = send(port, "POST", &vote, r#"{"user": "other_user"}"#);
+        println!("Voting for it gave {status}: {voted}");
+        assert_eq!(status, 200);
+        assert_eq!(voted["counted"], true);
+        let (_, voted) = send(port, "POST", &vote, r#"{"user": "other_user"}"#);
+        assert_eq!(voted["counted"], false);
+
+        let (status, articles) = send(port, "GET", "/articles?page=1", "");
+        println!("The first page of articles is {status}: {articles}");
+        assert_eq!(status, 200);
+        let article = articles
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|article| article["id"] == id.as_str())
+            .expect("The posted article should be on the first page");
+        assert_eq!(article["votes"], 2);
+
+        let (status, error) = send(port, "POST", "/articles/0/vote", r#"{"user": "username"}"#);
+        println!("Voting for a missing article gave {status}: {error}");
+        assert_eq!(status, 404);
+        let (status, _) = send(port, "POST", "/articles", r#"{"user": "username"}"#);
+        assert_eq!(status, 400);
+        let (status, error) = send(
+            port,
+            "POST",
+            "/articles",
+            r#"{"user": "username", "title": "A title", "link": "not a url"}"#,
+        );
+        println!("Posting a bad link gave {status}: {error}");
+        assert_eq!(status, 400);
+
+        server.unblock();
+        handle.join().unwrap().unwrap();
+
+        let mut conn = client.get_connection().unwrap();
+        let mut article = "article:".to_owned();
+        article.push_str(&id);
+        let mut voted = "voted:".to_owned();
+        voted.push_str(&id);
+        conn.del::<_, usize>(&[&article, &voted]).unwrap();
+        for order in ["score:", "time:", "votes:"] {
+            conn.zrem::<_, _, usize>(order, &article).unwrap();
+        }
+    }
+}
@@ -1,220 +1,4351 @@
 use std::{
+    cmp::{self, Ordering},
+    collections::{BTreeMap, HashMap, HashSet},
     error::Error,
+    fmt,
+    io::{BufRead, BufReader, Read, Write},
+    num::ParseIntError,
+    str::FromStr,
+    sync::OnceLock,
+    thread,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use redis::{Commands, Connection};
+use redis::{
+    streams::{StreamMaxlen, StreamRangeReply},
+    Commands, ConnectionLike, ErrorKind, RedisError, RedisResult,
+};
+use serde::{Deserialize, Serialize};
+use urlparse::urlparse;
 
 const ONE_WEEK_IN_SECONDS: usize = 7 * 86400;
-const VOTE_SCORE: usize = 432;
+// Redis stores sorted set scores as doubles, so they're handled as f64 throughout. Scores are built
+// from millisecond timestamps (~1.7e12 today) plus multiples of VOTE_SCORE; an f64 represents every
+// integer up to 2^53 (~9e15) exactly, so this math stays exact for a few hundred thousand years.
+const VOTE_SCORE: f64 = 432.0;
 const ARTICLES_PER_PAGE: isize = 25;
+const VOTE_STREAM_MAXLEN: usize = 10_000;
+// Groups with at most this many articles are ranked in-process by `rank_small_group`, rather than by
+// intersecting them with the whole of the order's ZSET
+const SMALL_GROUP_SIZE: usize = 100;
+// Votes cast within a group are tallied in `group_score:<group>`
+const GROUP_SCORE: &str = "group_score:";
+// The longest title `post_article` accepts unless told otherwise, in characters
+const MAX_TITLE_LENGTH: usize = 512;
+// The fields an article's extra metadata can't use: the ones in its HASH, plus the id and score that
+// are stored elsewhere but still show up alongside them, i.e. in exports
+const RESERVED_FIELDS: [&str; 7] = ["id", "link", "poster", "score", "time", "title", "votes"];
+// Every counted vote is announced on this channel as JSON, i.e.
+// {"user":"...","article":"article:1","direction":"up"}, or "down" for `article_downvote`
+pub const VOTE_CHANNEL: &str = "votes:notify";
+
+// The ZSETs articles can be ranked by. `Score` is the time an article was posted plus VOTE_SCORE per
+// vote, so older articles age out of it, whereas `Votes` is the raw number of votes ever cast.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArticleOrder {
+    Score,
+    Time,
+    Votes,
+}
+
+impl ArticleOrder {
+    pub fn key(&self) -> &'static str {
+        match self {
+            ArticleOrder::Score => "score:",
+            ArticleOrder::Time => "time:",
+            ArticleOrder::Votes => "votes:",
+        }
+    }
+}
+
+// Parses the name of one of the ranking ZSETs, i.e. "time:", so that a typo'd order is an error
+// rather than an intersection with a ZSET that doesn't exist.
+impl FromStr for ArticleOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [ArticleOrder::Score, ArticleOrder::Time, ArticleOrder::Votes]
+            .into_iter()
+            .find(|order| order.key() == s)
+            .ok_or_else(|| format!("'{s}' isn't an order; use 'score:', 'time:' or 'votes:'"))
+    }
+}
+
+// The number an article is stored under, i.e. 12 for `article:12`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ArticleId(pub u64);
+
+impl fmt::Display for ArticleId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for ArticleId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ArticleId(s.parse()?))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Article {
+    pub id: String,
+    pub title: String,
+    pub link: String,
+    pub poster: String,
+    pub time: u128,
+    pub votes: usize,
+    pub score: f64,
+    // Whatever else the app stored alongside the article when posting it, i.e. a category
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub extra: HashMap<String, String>,
+}
+
+impl Article {
+    // Builds an Article out of the fields stored in an `article:<id>` HASH. The score isn't part of
+    // the HASH, so it has to be looked up separately in `score:` and passed in. Any fields besides the
+    // usual ones are the article's extra metadata.
+    fn from_hash(
+        id: String,
+        mut hash: HashMap<String, String>,
+        score: f64,
+    ) -> Result<Article, Box<dyn Error>> {
+        let mut field = |name: &str| {
+            hash.remove(name)
+                .ok_or_else(|| format!("Article {id} is missing its '{name}' field"))
+        };
+        let (title, link, poster) = (field("title")?, field("link")?, field("poster")?);
+        let (time, votes) = (field("time")?.parse()?, field("votes")?.parse()?);
+        Ok(Article {
+            title,
+            link,
+            poster,
+            time,
+            votes,
+            extra: hash,
+            id,
+            score,
+        })
+    }
+
+    // When the article was posted as an ISO-8601/RFC 3339 UTC timestamp with millisecond precision,
+    // i.e. `2022-07-01T12:00:00.000Z`. None if `time` is too far out for chrono to represent, which
+    // an article read back from a corrupted HASH could be.
+    #[cfg(feature = "rfc3339")]
+    pub fn time_rfc3339(&self) -> Option<String> {
+        use chrono::{SecondsFormat, TimeZone, Utc};
+
+        let millis = i64::try_from(self.time).ok()?;
+        let time = Utc.timestamp_millis_opt(millis).single()?;
+        Some(time.to_rfc3339_opts(SecondsFormat::Millis, true))
+    }
+
+    // How long ago the article was posted. Clock skew can put `time` slightly in the future, in which
+    // case the article is treated as brand new.
+    pub fn age(&self) -> Duration {
+        let posted = UNIX_EPOCH + Duration::from_millis(self.time as u64);
+        SystemTime::now()
+            .duration_since(posted)
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+// Scores are never NaN (they're built from timestamps and vote counts), so every Article is equal to
+// itself and this is sound
+impl Eq for Article {}
+
+// Articles sort with the most voted first, and the newest first among those with as many votes. The
+// rest of the fields only break ties, so that two articles only compare equal if they're identical.
+impl Ord for Article {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .votes
+            .cmp(&self.votes)
+            .then_with(|| other.time.cmp(&self.time))
+            .then_with(|| self.id.cmp(&other.id))
+            .then_with(|| self.title.cmp(&other.title))
+            .then_with(|| self.link.cmp(&other.link))
+            .then_with(|| self.poster.cmp(&other.poster))
+            .then_with(|| self.score.total_cmp(&other.score))
+            .then_with(|| {
+                let sorted = |extra: &HashMap<String, String>| {
+                    extra.iter().collect::<BTreeMap<&String, &String>>()
+                };
+                sorted(&self.extra).cmp(&sorted(&other.extra))
+            })
+    }
+}
+
+impl PartialOrd for Article {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Sorts articles the way `score:` ranks them, highest score first, falling back to the order above
+// for articles with the same score.
+pub fn sort_by_score(articles: &mut [Article]) {
+    articles.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.cmp(b)));
+}
+
+// Everything in an article's HASH is stored as a string, but since `Article` parses `time` and
+// `votes` on the way out of Redis, they come out as proper JSON numbers here.
+pub fn articles_to_json(articles: &[Article]) -> serde_json::Value {
+    serde_json::to_value(articles).expect("Articles should always be serializable")
+}
+
+// Errors for requests the library refuses outright, as opposed to Redis failing, so that callers can
+// tell them apart (i.e. to answer with a 400 rather than a 500) by downcasting the `Box<dyn Error>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AppError {
+    BadInput(String),
+    // The key of something that doesn't exist, i.e. `article:12`
+    NotFound(String),
+    // The key of something that already exists but shouldn't, i.e. an `article:12` that a new post
+    // was about to be written over
+    Conflict(String),
+    // The key of an article that's past the week long voting window
+    TooOld(String),
+    // A user who's doing something more often than they're allowed to. Nothing in here limits them;
+    // it's for embedders that put limits of their own in front of these functions.
+    RateLimited(String),
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::BadInput(reason) => write!(f, "Bad input: {reason}"),
+            AppError::NotFound(key) => write!(f, "{key} doesn't exist"),
+            AppError::Conflict(key) => write!(f, "{key} already exists"),
+            AppError::TooOld(key) => write!(f, "{key} was posted too long ago to vote on"),
+            AppError::RateLimited(user) => write!(f, "{user} is voting too often"),
+        }
+    }
+}
+
+impl Error for AppError {}
+
+// What's safe to send back to a client about an error, i.e. as the JSON body of an HTTP response.
+// `AppError`s keep their message under a code of their own; any other error (Redis being down, a
+// malformed HASH) is just an "internal" one, so that no details of the server leak out.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClientError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl From<AppError> for ClientError {
+    fn from(e: AppError) -> ClientError {
+        let code = match e {
+            AppError::BadInput(_) => "bad_input",
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::TooOld(_) => "too_old",
+            AppError::RateLimited(_) => "rate_limited",
+        };
+        ClientError {
+            code,
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<Box<dyn Error>> for ClientError {
+    fn from(e: Box<dyn Error>) -> ClientError {
+        match e.downcast::<AppError>() {
+            Ok(e) => ClientError::from(*e),
+            Err(_) => ClientError {
+                code: "internal",
+                message: "internal error".to_owned(),
+            },
+        }
+    }
+}
+
+// Where "now" comes from when deciding whether an article is still open for voting or stamping a new
+// one. `Redis` asks the server with TIME, which costs a round-trip but means every app server agrees on
+// the time even if their own clocks have drifted apart.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClockSource {
+    #[default]
+    Local,
+    Redis,
+}
+
+impl ClockSource {
+    // The time since the Unix epoch according to this clock
+    pub fn now(self, conn: &mut impl ConnectionLike) -> Result<Duration, Box<dyn Error>> {
+        match self {
+            ClockSource::Local => Ok(SystemTime::now().duration_since(UNIX_EPOCH)?),
+            ClockSource::Redis => {
+                // TIME replies with the seconds and the microseconds into the current second
+                let (secs, micros): (u64, u64) = redis::cmd("TIME").query(conn)?;
+                Ok(Duration::from_secs(secs) + Duration::from_micros(micros))
+            }
+        }
+    }
+}
 
-type Article = Vec<(String, String)>;
+// Runs `op` up to `attempts` times, for riding out failovers and dropped connections. Only errors that
+// mean Redis couldn't be reached (or asked to be retried) are retried, waiting `base_delay` and then
+// twice as long after each failure; anything else, i.e. a WRONGTYPE, is handed straight back since
+// trying again won't change the answer.
+pub fn with_retry<T>(
+    attempts: usize,
+    base_delay: Duration,
+    mut op: impl FnMut() -> RedisResult<T>,
+) -> RedisResult<T> {
+    let mut delay = base_delay;
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(e) if attempt < attempts && is_transient(&e) => {
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_transient(e: &RedisError) -> bool {
+    e.is_io_error()
+        || e.is_timeout()
+        || e.is_connection_dropped()
+        || e.is_connection_refusal()
+        || matches!(e.kind(), ErrorKind::TryAgain | ErrorKind::BusyLoadingError)
+}
+
+// Decides what the keys voting touches are actually called, given their plain names (i.e. `score:` or
+// `voted:<id>`), like ch02's `KeyScheme`. `DefaultScheme` keeps the plain names and `Namespace`
+// prefixes them, so an article's id is whatever follows the scheme's `article:` in its key.
+pub trait KeyScheme {
+    fn key(&self, name: &str) -> String;
+
+    // The id at the end of an article's key, i.e. "12" for `article:12`, or None if `article` isn't
+    // one of this scheme's article keys
+    fn article_id<'a>(&self, article: &'a str) -> Option<&'a str> {
+        article.strip_prefix(self.key("article:").as_str())
+    }
+
+    // The SET of users who voted for an article
+    fn voted(&self, article_id: &str) -> String {
+        let mut key = "voted:".to_owned();
+        key.push_str(article_id);
+        self.key(&key)
+    }
+
+    // The SET of users who voted against an article (see `article_downvote`)
+    fn against(&self, article_id: &str) -> String {
+        let mut key = "against:".to_owned();
+        key.push_str(article_id);
+        self.key(&key)
+    }
+
+    // The HASH of how much each non-plain vote for an article counted
+    fn vote_weights(&self, article_id: &str) -> String {
+        let mut key = "vote_weight:".to_owned();
+        key.push_str(article_id);
+        self.key(&key)
+    }
+
+    fn score(&self) -> String {
+        self.key("score:")
+    }
+
+    fn time(&self) -> String {
+        self.key("time:")
+    }
+
+    fn votes(&self) -> String {
+        self.key("votes:")
+    }
+}
+
+// The keys exactly as they're named above, which is what every function without a scheme uses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DefaultScheme;
+
+impl KeyScheme for DefaultScheme {
+    fn key(&self, name: &str) -> String {
+        name.to_owned()
+    }
+}
+
+// Prefixes every key, i.e. `voted:12` becomes `tenantA:voted:12` and `article:12` has to be
+// `tenantA:article:12` under `Namespace::new("tenantA")`. An empty namespace leaves keys as they are.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Namespace(pub String);
+
+impl Namespace {
+    pub fn new<S: Into<String>>(prefix: S) -> Namespace {
+        Namespace(prefix.into())
+    }
+}
+
+impl KeyScheme for Namespace {
+    fn key(&self, name: &str) -> String {
+        if self.0.is_empty() {
+            return name.to_owned();
+        }
+        let mut key = self.0.clone();
+        key.push(':');
+        key.push_str(name);
+        key
+    }
+}
 
 // Some generic traits that implement Into<String> were thrown in haphazardly because I initially
 // had it accept strings only to realize it's nicer for the arguments to accept &str for testing.
 // I made some parameters generic because that's ultimately what would be done if we were to actually
 // build this out, but only the ones that were easy to do and reduced the time to create the test.
-pub fn article_vote<S>(conn: &mut Connection, user: S, article: S) -> Result<(), Box<dyn Error>>
+// Parameters that are only used to build keys take AsRef<str> instead, so passing a &str doesn't
+// allocate a String; only the ones that end up stored (i.e. in an `Article`) are Into<String>.
+// Returns whether the vote was counted, i.e. false if `user` had already voted for `article`.
+pub fn article_vote<S>(
+    conn: &mut impl ConnectionLike,
+    user: S,
+    article: S,
+) -> Result<bool, Box<dyn Error>>
 where
-    S: Into<String>,
+    S: AsRef<str>,
 {
-    let user = user.into();
-    let article = article.into();
-
-    let cutoff = SystemTime::now() - Duration::from_secs(ONE_WEEK_IN_SECONDS as u64);
-    let creation_time: u128 = conn.zscore("time:", &article)?;
-    if creation_time < cutoff.duration_since(UNIX_EPOCH)?.as_millis() {
-        return Err("Cannot upvote posts created more than a week ago.".into());
-    }
-    let article_id = article
-        .split(':')
-        .collect::<Vec<_>>()
-        .pop()
-        .expect("Articles should be namespaced with 'article:'");
-    let mut article_votes = "voted:".to_owned();
-    article_votes.push_str(article_id);
-    if conn.sadd(article_votes, user)? {
-        conn.zincr("score:", &article, VOTE_SCORE)?;
-        conn.hincr(&article, "votes", 1)?;
+    article_vote_weighted(conn, user, article, 1)
+}
+
+// A vote that counts `weight` times, e.g. for moderators or subscribers. Anything other than a plain
+// vote is recorded in `vote_weight:<id>` so that it can be reversed by exactly the right amount.
+pub fn article_vote_weighted<S>(
+    conn: &mut impl ConnectionLike,
+    user: S,
+    article: S,
+    weight: usize,
+) -> Result<bool, Box<dyn Error>>
+where
+    S: AsRef<str>,
+{
+    article_vote_with_clock(conn, user, article, weight, ClockSource::Local)
+}
+
+// Casts a vote. Everything after the SADD, including the side effects (the VOTE_CHANNEL notification
+// and, with the `vote-log` feature, the `votes:stream` entry), only happens if the SADD added the
+// user, all inside one script. So when two app instances race to record the same vote, only one of
+// them counts it and it's only ever announced once. A user who already voted against the article (in
+// KEYS[7]) can't vote for it as well.
+const VOTE_SCRIPT: &str = r"
+if redis.call('SISMEMBER', KEYS[7], ARGV[1]) == 1 then
+    return 0
+end
+if redis.call('SADD', KEYS[1], ARGV[1]) == 0 then
+    return 0
+end
+local weight = tonumber(ARGV[2])
+if weight ~= 1 then
+    redis.call('HSET', KEYS[2], ARGV[1], weight)
+    redis.call('EXPIRE', KEYS[2], ARGV[4])
+end
+redis.call('ZINCRBY', KEYS[3], weight * tonumber(ARGV[3]), KEYS[4])
+redis.call('ZINCRBY', KEYS[5], weight, KEYS[4])
+redis.call('HINCRBY', KEYS[4], 'votes', weight)
+redis.call('PUBLISH', ARGV[5],
+    cjson.encode({user = ARGV[1], article = KEYS[4], direction = 'up'}))
+if ARGV[6] == '1' then
+    redis.call('XADD', KEYS[6], 'MAXLEN', '~', ARGV[7], '*', 'user', ARGV[1], 'article', KEYS[4],
+        'direction', 'up', 'ts', ARGV[8])
+end
+return 1
+";
+
+// Same as `article_vote_weighted`, but the week long voting window is measured against `clock`.
+// Voting on an article that doesn't exist is an `AppError::NotFound`.
+pub fn article_vote_with_clock<S>(
+    conn: &mut impl ConnectionLike,
+    user: S,
+    article: S,
+    weight: usize,
+    clock: ClockSource,
+) -> Result<bool, Box<dyn Error>>
+where
+    S: AsRef<str>,
+{
+    article_vote_with_scheme(conn, &DefaultScheme, user, article, weight, clock)
+}
+
+// The vote itself, for an article whose keys (and those of its votes and rankings) are named by
+// `scheme`. An article key outside of the scheme is an `AppError::BadInput`, and one that's more than
+// a week old is an `AppError::TooOld`.
+pub fn article_vote_with_scheme<S>(
+    conn: &mut impl ConnectionLike,
+    scheme: &impl KeyScheme,
+    user: S,
+    article: S,
+    weight: usize,
+    clock: ClockSource,
+) -> Result<bool, Box<dyn Error>>
+where
+    S: AsRef<str>,
+{
+    let (user, article) = (user.as_ref(), article.as_ref());
+    check_voting_window(conn, scheme, article, clock)?;
+    let script = redis::Script::new(VOTE_SCRIPT);
+    let mut invocation = script.prepare_invoke();
+    add_vote_keys_and_args(&mut invocation, scheme, user, article, weight)?;
+    let counted: i64 = invocation.invoke(conn)?;
+    Ok(counted == 1)
+}
+
+// Adds the KEYS and ARGV that VOTE_SCRIPT reads to `invocation`, for it or a script that embeds it
+fn add_vote_keys_and_args(
+    invocation: &mut redis::ScriptInvocation,
+    scheme: &impl KeyScheme,
+    user: &str,
+    article: &str,
+    weight: usize,
+) -> Result<(), Box<dyn Error>> {
+    let article_id = scheme_article_id(scheme, article)?;
+    let ts = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+
+    invocation
+        .key(scheme.voted(article_id))
+        .key(scheme.vote_weights(article_id))
+        .key(scheme.score())
+        .key(article)
+        .key(scheme.votes())
+        .key(scheme.key("votes:stream"))
+        .key(scheme.against(article_id))
+        .arg(user)
+        .arg(weight)
+        .arg(VOTE_SCORE)
+        .arg(ONE_WEEK_IN_SECONDS)
+        .arg(VOTE_CHANNEL)
+        .arg(cfg!(feature = "vote-log") as u8)
+        .arg(VOTE_STREAM_MAXLEN)
+        .arg(ts.to_string());
+    Ok(())
+}
+
+// Articles can only be voted on (for or against) for a week after they're posted, going by `clock`
+fn check_voting_window(
+    conn: &mut impl ConnectionLike,
+    scheme: &impl KeyScheme,
+    article: &str,
+    clock: ClockSource,
+) -> Result<(), Box<dyn Error>> {
+    let cutoff = clock
+        .now(conn)?
+        .saturating_sub(Duration::from_secs(ONE_WEEK_IN_SECONDS as u64));
+    // An article that was never posted (or was deleted) has no time to check against
+    let creation_time: Option<f64> = conn.zscore(scheme.time(), article)?;
+    let creation_time = creation_time.ok_or_else(|| AppError::NotFound(article.to_owned()))?;
+    if creation_time < cutoff.as_millis() as f64 {
+        return Err(AppError::TooOld(article.to_owned()).into());
     }
     Ok(())
 }
 
-pub fn post_article<S>(
-    conn: &mut Connection,
+fn scheme_article_id<'a>(scheme: &impl KeyScheme, article: &'a str) -> Result<&'a str, AppError> {
+    scheme.article_id(article).ok_or_else(|| {
+        AppError::BadInput(format!(
+            "{article} isn't an article key, which should start with {}",
+            scheme.key("article:")
+        ))
+    })
+}
+
+// Takes back a vote, using the weight recorded in `vote_weight:<id>` (or 1 if there isn't one). It
+// runs as a script so that the removal from `voted:<id>` and the score/votes adjustments can't be
+// split up by a concurrent vote/unvote and drift apart. A vote against the article (in KEYS[6]) is
+// taken back the same way, giving back the VOTE_SCORE it took off.
+const UNVOTE_SCRIPT: &str = r"
+if redis.call('SREM', KEYS[6], ARGV[1]) == 1 then
+    redis.call('ZINCRBY', KEYS[3], tonumber(ARGV[2]), KEYS[4])
+    return 1
+end
+if redis.call('SREM', KEYS[1], ARGV[1]) == 0 then
+    return 0
+end
+local weight = tonumber(redis.call('HGET', KEYS[2], ARGV[1]) or 1)
+redis.call('HDEL', KEYS[2], ARGV[1])
+redis.call('ZINCRBY', KEYS[3], -weight * tonumber(ARGV[2]), KEYS[4])
+redis.call('ZINCRBY', KEYS[5], -weight, KEYS[4])
+redis.call('HINCRBY', KEYS[4], 'votes', -weight)
+return 1
+";
+
+// Retracts `user`'s vote for (or against) `article`, returning false if they hadn't voted on it. An
+// article that was hidden for its downvotes stays hidden until `unhide_article` is called.
+pub fn article_unvote<S>(
+    conn: &mut impl ConnectionLike,
     user: S,
-    title: S,
-    link: S,
-) -> Result<String, Box<dyn Error>>
+    article: S,
+) -> Result<bool, Box<dyn Error>>
 where
-    S: Into<String>,
+    S: AsRef<str>,
 {
-    let user = user.into();
-    let title = title.into();
-    let link = link.into();
+    article_unvote_with_scheme(conn, &DefaultScheme, user, article)
+}
+
+pub fn article_unvote_with_scheme<S>(
+    conn: &mut impl ConnectionLike,
+    scheme: &impl KeyScheme,
+    user: S,
+    article: S,
+) -> Result<bool, Box<dyn Error>>
+where
+    S: AsRef<str>,
+{
+    let user = user.as_ref();
+    let article = article.as_ref();
 
-    // Attempting `article_id: String` off the bat throws an error.
-    let article_id: usize = conn.incr("article:", 1)?;
-    let article_id: String = article_id.to_string();
+    let article_id = scheme_article_id(scheme, article)?;
+    let removed: usize = redis::Script::new(UNVOTE_SCRIPT)
+        .key(scheme.voted(article_id))
+        .key(scheme.vote_weights(article_id))
+        .key(scheme.score())
+        .key(&article)
+        .key(scheme.votes())
+        .key(scheme.against(article_id))
+        .arg(&user)
+        .arg(VOTE_SCORE)
+        .invoke(conn)?;
+    Ok(removed == 1)
+}
 
-    let mut voted = "voted:".to_owned();
-    voted.push_str(&article_id);
-    conn.sadd(&voted, &user)?;
-    conn.expire(&voted, ONE_WEEK_IN_SECONDS)?;
+// The SET of articles that are left out of the feeds, i.e. for having too many downvotes
+const HIDDEN: &str = "hidden:";
+
+// A downvote goes in `against:<id>` and takes VOTE_SCORE off the article's score, unless the user
+// already voted for or against it. Once `hide_threshold` users are against the article, it's hidden.
+// Like an upvote, a counted downvote is announced on VOTE_CHANNEL and, with the `vote-log` feature,
+// logged to `votes:stream`.
+const DOWNVOTE_SCRIPT: &str = r"
+if redis.call('SISMEMBER', KEYS[1], ARGV[1]) == 1 then
+    return 0
+end
+if redis.call('SADD', KEYS[2], ARGV[1]) == 0 then
+    return 0
+end
+redis.call('ZINCRBY', KEYS[3], -tonumber(ARGV[2]), KEYS[4])
+local threshold = tonumber(ARGV[3])
+if threshold > 0 and redis.call('SCARD', KEYS[2]) >= threshold then
+    redis.call('SADD', KEYS[5], KEYS[4])
+end
+redis.call('PUBLISH', ARGV[4],
+    cjson.encode({user = ARGV[1], article = KEYS[4], direction = 'down'}))
+if ARGV[5] == '1' then
+    redis.call('XADD', KEYS[6], 'MAXLEN', '~', ARGV[6], '*', 'user', ARGV[1], 'article', KEYS[4],
+        'direction', 'down', 'ts', ARGV[7])
+end
+return 1
+";
+
+// Votes against `article`, returning whether the downvote was counted. With a `hide_threshold`, the
+// article is hidden from `get_articles` and the other feeds as soon as that many users are against it,
+// until `unhide_article` is called. The same week long voting window (and `AppError::TooOld`) as
+// `article_vote` applies.
+pub fn article_downvote<S>(
+    conn: &mut impl ConnectionLike,
+    user: S,
+    article: S,
+    hide_threshold: Option<usize>,
+) -> Result<bool, Box<dyn Error>>
+where
+    S: AsRef<str>,
+{
+    article_downvote_with_scheme(
+        conn,
+        &DefaultScheme,
+        user,
+        article,
+        hide_threshold,
+        ClockSource::Local,
+    )
+}
+
+// The downvote itself, with its keys named by `scheme` and the voting window measured against
+// `clock`, the same as `article_vote_with_scheme`.
+pub fn article_downvote_with_scheme<S>(
+    conn: &mut impl ConnectionLike,
+    scheme: &impl KeyScheme,
+    user: S,
+    article: S,
+    hide_threshold: Option<usize>,
+    clock: ClockSource,
+) -> Result<bool, Box<dyn Error>>
+where
+    S: AsRef<str>,
+{
+    let user = user.as_ref();
+    let article = article.as_ref();
+
+    check_voting_window(conn, scheme, article, clock)?;
+    let article_id = scheme_article_id(scheme, article)?;
+    let ts = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+
+    let counted: usize = redis::Script::new(DOWNVOTE_SCRIPT)
+        .key(scheme.voted(article_id))
+        .key(scheme.against(article_id))
+        .key(scheme.score())
+        .key(article)
+        .key(scheme.key(HIDDEN))
+        .key(scheme.key("votes:stream"))
+        .arg(user)
+        .arg(VOTE_SCORE)
+        .arg(hide_threshold.unwrap_or(0))
+        .arg(VOTE_CHANNEL)
+        .arg(cfg!(feature = "vote-log") as u8)
+        .arg(VOTE_STREAM_MAXLEN)
+        .arg(ts.to_string())
+        .invoke(conn)?;
+    Ok(counted == 1)
+}
+
+// Puts a hidden article back in the feeds, returning whether it was hidden. Its downvotes are left as
+// they are, so the next one hides it again if it's still over the threshold.
+pub fn unhide_article<S>(conn: &mut impl ConnectionLike, article: S) -> Result<bool, Box<dyn Error>>
+where
+    S: AsRef<str>,
+{
+    let removed: usize = conn.srem(HIDDEN, article.as_ref())?;
+    Ok(removed == 1)
+}
+
+// Which of `keys` are hidden, checked in a single pipeline
+fn hidden_among(
+    conn: &mut impl ConnectionLike,
+    keys: &[String],
+) -> Result<HashSet<String>, Box<dyn Error>> {
+    if keys.is_empty() {
+        return Ok(HashSet::new());
+    }
+    let mut pipe = redis::pipe();
+    for key in keys {
+        pipe.sismember(HIDDEN, key);
+    }
+    let hidden: Vec<bool> = pipe.query(conn)?;
+    Ok(keys
+        .iter()
+        .zip(hidden)
+        .filter(|(_, hidden)| *hidden)
+        .map(|(key, _)| key.clone())
+        .collect())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoteEvent {
+    pub id: String,
+    pub user: String,
+    pub article: String,
+    pub direction: String,
+    pub ts: u128,
+}
 
-    let now = SystemTime::now()
+// Appends a vote to the `votes:stream` audit log, named by `scheme` like the rest of voting's keys.
+// The stream is capped (approximately, which lets Redis trim whole nodes at a time) so that it can't
+// grow without bound.
+pub fn log_vote_event(
+    conn: &mut impl ConnectionLike,
+    scheme: &impl KeyScheme,
+    user: &str,
+    article: &str,
+    direction: &str,
+) -> Result<String, Box<dyn Error>> {
+    let ts = SystemTime::now()
         .duration_since(UNIX_EPOCH)?
         .as_millis()
         .to_string();
-    let mut article = "article:".to_owned();
-    article.push_str(&article_id);
-    conn.hset_multiple(
-        &article,
+    Ok(conn.xadd_maxlen(
+        scheme.key("votes:stream"),
+        StreamMaxlen::Approx(VOTE_STREAM_MAXLEN),
+        "*",
         &[
-            ("title", &title),
-            ("link", &link),
-            ("poster", &user),
-            ("time", &now),
-            ("votes", &(1_usize).to_string()),
+            ("user", user),
+            ("article", article),
+            ("direction", direction),
+            ("ts", ts.as_str()),
         ],
-    )?;
-
-    conn.zadd(
-        "score:",
-        &article,
-        now.parse::<usize>().unwrap() + VOTE_SCORE,
-    )?;
-    conn.zadd("time:", &article, &now)?;
-
-    Ok(article_id)
+    )?)
 }
 
-pub fn get_articles(
-    conn: &mut Connection,
-    page: isize,
-    order: Option<String>,
-) -> Result<Vec<Article>, Box<dyn Error>> {
-    let start = (page - 1) * ARTICLES_PER_PAGE;
-    let end = start + ARTICLES_PER_PAGE - 1;
-
-    let ids: Vec<String> =
-        conn.zrevrange(order.unwrap_or_else(|| "score:".to_owned()), start, end)?;
-    let mut articles = vec![];
-    for id in ids {
-        let mut article_data: Article = conn.hgetall(&id)?;
-        article_data.push(("id".to_owned(), id));
-        articles.push(article_data);
+// The oldest `count` events still in `scheme`'s `votes:stream` audit log.
+pub fn read_vote_events(
+    conn: &mut impl ConnectionLike,
+    scheme: &impl KeyScheme,
+    count: usize,
+) -> Result<Vec<VoteEvent>, Box<dyn Error>> {
+    let reply: StreamRangeReply = conn.xrange_count(scheme.key("votes:stream"), "-", "+", count)?;
+    let mut events = vec![];
+    for entry in reply.ids {
+        let mut field = |name: &str| {
+            entry
+                .get::<String>(name)
+                .ok_or_else(|| format!("Vote event {} is missing its '{name}' field", entry.id))
+        };
+        events.push(VoteEvent {
+            user: field("user")?,
+            article: field("article")?,
+            direction: field("direction")?,
+            ts: field("ts")?.parse()?,
+            id: entry.id,
+        });
     }
-    Ok(articles)
+    Ok(events)
 }
 
-pub fn add_remove_groups<Q, R, S>(
-    conn: &mut Connection,
-    article_id: Q,
-    to_add: Vec<R>,
-    to_remove: Vec<S>,
-) -> Result<(), Box<dyn Error>>
+pub fn post_article<S>(
+    conn: &mut impl ConnectionLike,
+    user: S,
+    title: S,
+    link: S,
+) -> Result<ArticleId, Box<dyn Error>>
 where
-    Q: Into<String>,
-    R: Into<String>,
     S: Into<String>,
 {
-    let mut article = "article:".to_owned();
-    article.push_str(&article_id.into());
+    Ok(post_article_full(conn, user, title, link)?.id.parse()?)
+}
 
-    for group_label in to_add {
-        let mut group = "group:".to_owned();
-        group.push_str(&group_label.into());
-        conn.sadd(group, &article)?;
-    }
+// Where a freshly posted article was put in the rankings: its id along with the time (in milliseconds)
+// and initial score (the time plus VOTE_SCORE) it was given
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PostedArticle {
+    pub id: ArticleId,
+    pub score: f64,
+    pub time: u128,
+}
 
-    for group_label in to_remove {
-        let mut group = "group:".to_owned();
-        group.push_str(&group_label.into());
-        conn.srem(group, &article)?;
-    }
-    Ok(())
+// Same as `post_article`, but also hands back the article's initial score and time, so that callers
+// ranking it straight away don't need to ZSCORE it.
+pub fn post_article_scored<S>(
+    conn: &mut impl ConnectionLike,
+    user: S,
+    title: S,
+    link: S,
+) -> Result<PostedArticle, Box<dyn Error>>
+where
+    S: Into<String>,
+{
+    let article = post_article_full(conn, user, title, link)?;
+    Ok(PostedArticle {
+        id: article.id.parse()?,
+        score: article.score,
+        time: article.time,
+    })
 }
 
-pub fn get_group_articles<S>(
-    conn: &mut Connection,
-    group: S,
-    page: isize,
-    order: Option<String>,
-) -> Result<Vec<Article>, Box<dyn Error>>
+// Same as `post_article`, but hands back everything that was just written so that callers don't
+// have to immediately turn around and HGETALL the article to display it.
+pub fn post_article_full<S>(
+    conn: &mut impl ConnectionLike,
+    user: S,
+    title: S,
+    link: S,
+) -> Result<Article, Box<dyn Error>>
 where
     S: Into<String>,
 {
-    let group = group.into();
-    let order = order.unwrap_or_else(|| "score:".to_owned());
-    let mut key = order.to_owned();
-    key.push_str(&group);
-    if !conn.exists(&key)? {
-        let mut group_key = "group:".to_owned();
-        group_key.push_str(&group);
-        conn.zinterstore_max(&key, &[&group_key, &order])?;
-        conn.expire(&key, 60)?;
-    }
-    get_articles(conn, page, Some(key))
+    post_article_tagged(conn, user, title, link, &[])
 }
 
-#[cfg(test)]
-mod tests {
-    use redis::Commands;
+// Same as `post_article_full`, but also adds the article to `group:<tag>` for each of `tags`. The
+// article and its groups are written in one MULTI/EXEC, so there's no window where the article can be
+// seen but isn't in its groups yet.
+pub fn post_article_tagged<S>(
+    conn: &mut impl ConnectionLike,
+    user: S,
+    title: S,
+    link: S,
+    tags: &[&str],
+) -> Result<Article, Box<dyn Error>>
+where
+    S: Into<String>,
+{
+    post_article_with_clock(conn, user, title, link, tags, ClockSource::Local)
+}
 
-    use crate::{
-        add_remove_groups, article_vote, get_articles, get_group_articles, post_article, Article,
+// Same as `post_article_full`, but also stores each of `extra`'s fields in the article's HASH, where
+// `get_article` hands them back in `Article::extra`. None of them may be one of the article's own
+// fields (title, link, ...), which is an `AppError::BadInput`.
+pub fn post_article_with_extra<S>(
+    conn: &mut impl ConnectionLike,
+    user: S,
+    title: S,
+    link: S,
+    extra: &[(&str, &str)],
+) -> Result<Article, Box<dyn Error>>
+where
+    S: Into<String>,
+{
+    let options = PostOptions {
+        extra: extra
+            .iter()
+            .map(|(field, value)| (field.to_string(), value.to_string()))
+            .collect(),
+        ..PostOptions::default()
     };
+    post_article_with(conn, user, title, link, &options)
+}
 
-    // Execute`cargo test -p ch01 -- --nocapture` to run these tests
-    #[test]
-    fn test_article_functionality() {
-        let mut conn = redis::Client::open("redis://127.0.0.1")
-            .expect("Should be able to reach Redis Server")
-            .get_connection()
-            .expect("Should be able to Establish Connection");
+// Same as `post_article_tagged`, but the article's time (and so its initial score) comes from
+// `clock`.
+pub fn post_article_with_clock<S>(
+    conn: &mut impl ConnectionLike,
+    user: S,
+    title: S,
+    link: S,
+    tags: &[&str],
+    clock: ClockSource,
+) -> Result<Article, Box<dyn Error>>
+where
+    S: Into<String>,
+{
+    let options = PostOptions {
+        tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        clock,
+        ..PostOptions::default()
+    };
+    post_article_with(conn, user, title, link, &options)
+}
 
-        let article_id =
-            post_article(&mut conn, "username", "A title", "http://google.com").unwrap();
-        let mut article = "article:".to_owned();
-        article.push_str(&article_id);
-        println!("We posted a new article with id {article_id} ({article})\n");
-        assert!(!article_id.is_empty());
+#[derive(Debug, Clone)]
+pub struct PostOptions {
+    // Groups to add the article to, as with `post_article_tagged`
+    pub tags: Vec<String>,
+    // Where the article's time comes from, as with `post_article_with_clock`
+    pub clock: ClockSource,
+    // The URL schemes a link may have; only http and https are allowed by default
+    pub link_schemes: Vec<String>,
+    // How many characters a title may have once leading and trailing whitespace is trimmed off. An
+    // empty title is never allowed, whatever the minimum is.
+    pub min_title_length: usize,
+    pub max_title_length: usize,
+    // Extra fields to store in the article's HASH, as with `post_article_with_extra`
+    pub extra: Vec<(String, String)>,
+}
 
-        println!("Its HASH looks like:");
-        let r: Article = conn.hgetall(&article).unwrap();
-        println!("{:#?}\n", r);
-        assert!(r.len() != 0);
+impl Default for PostOptions {
+    fn default() -> Self {
+        PostOptions {
+            tags: vec![],
+            clock: ClockSource::Local,
+            link_schemes: vec!["http".to_owned(), "https".to_owned()],
+            min_title_length: 1,
+            max_title_length: MAX_TITLE_LENGTH,
+            extra: vec![],
+        }
+    }
+}
 
-        article_vote(&mut conn, "other_user", &article).unwrap();
+// The most general way to post an article. Nothing is written if the link isn't a URL with one of the
+// allowed schemes and a host, the title isn't within the allowed length, or an extra field would
+// overwrite one of the article's own, in which case the error is an `AppError::BadInput`. The title is
+// stored with leading and trailing whitespace trimmed off.
+pub fn post_article_with<S>(
+    conn: &mut impl ConnectionLike,
+    user: S,
+    title: S,
+    link: S,
+    options: &PostOptions,
+) -> Result<Article, Box<dyn Error>>
+where
+    S: Into<String>,
+{
+    let user = user.into();
+    let title = check_title(title.into(), options)?;
+    let link = link.into();
+    check_link(&link, &options.link_schemes)?;
+    check_extra(&options.extra)?;
+    let clock = options.clock;
+    let tags = &options.tags;
+
+    // Redis counters are signed 64 bit integers, and INCR itself errors rather than wrapping around
+    // once that overflows, so all that's left to check is that the counter wasn't set negative.
+    let article_id: i64 = conn.incr("article:", 1)?;
+    let article_id = u64::try_from(article_id)
+        .map_err(|_| format!("The article: counter is negative ({article_id})"))?;
+    let article_id = ArticleId(article_id).to_string();
+
+    let mut voted = "voted:".to_owned();
+    voted.push_str(&article_id);
+    let article_groups = article_groups_key(&article_id);
+
+    let now = clock.now(conn)?.as_millis();
+    let mut article = "article:".to_owned();
+    article.push_str(&article_id);
+    let score = now as f64 + VOTE_SCORE;
+
+    // The id should be brand new, but if the `article:` counter was ever set back an article could
+    // already be using it. The article is WATCHed while that's checked, so one that shows up in the
+    // meantime fails the check on a retry instead of being written over.
+    let posted = redis::transaction(conn, &[&article], |conn, pipe| {
+        let exists: bool = conn.exists(&article)?;
+        if exists {
+            return Ok(Some(false));
+        }
+        pipe.sadd(&voted, &user)
+            .ignore()
+            .expire(&voted, ONE_WEEK_IN_SECONDS)
+            .ignore()
+            .hset_multiple(
+                &article,
+                &[
+                    ("title", &title),
+                    ("link", &link),
+                    ("poster", &user),
+                    ("time", &now.to_string()),
+                    ("votes", &(1_usize).to_string()),
+                ],
+            )
+            .ignore();
+        if !options.extra.is_empty() {
+            pipe.hset_multiple(&article, &options.extra).ignore();
+        }
+        pipe.cmd("ZADD")
+            .arg("score:")
+            .arg("GT")
+            .arg(score)
+            .arg(&article)
+            .ignore()
+            .cmd("ZADD")
+            .arg("time:")
+            .arg("NX")
+            .arg(now as f64)
+            .arg(&article)
+            .ignore()
+            .zadd("votes:", &article, 1)
+            .ignore();
+        for tag in tags {
+            let mut group = "group:".to_owned();
+            group.push_str(tag);
+            pipe.sadd(group, &article)
+                .ignore()
+                .sadd("groups:", tag)
+                .ignore()
+                .sadd(&article_groups, tag)
+                .ignore();
+        }
+        let written: Option<()> = pipe.query(conn)?;
+        Ok(written.map(|()| true))
+    })?;
+    if !posted {
+        return Err(AppError::Conflict(article).into());
+    }
+    // Same as when an article is added to a group after it's posted
+    for tag in tags {
+        invalidate_group_cache(conn, tag)?;
+    }
+
+    Ok(Article {
+        id: article_id,
+        title,
+        link,
+        poster: user,
+        time: now,
+        votes: 1,
+        score,
+        extra: options.extra.iter().cloned().collect(),
+    })
+}
+
+fn check_extra(extra: &[(String, String)]) -> Result<(), AppError> {
+    match extra
+        .iter()
+        .find(|(field, _)| RESERVED_FIELDS.contains(&field.as_str()))
+    {
+        Some((field, _)) => Err(AppError::BadInput(format!(
+            "'{field}' is one of the article's own fields, so it can't be used for extra metadata"
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn check_title(title: String, options: &PostOptions) -> Result<String, AppError> {
+    let title = title.trim();
+    let length = title.chars().count();
+    if length == 0 {
+        return Err(AppError::BadInput("The title is empty".to_owned()));
+    }
+    if length < options.min_title_length || length > options.max_title_length {
+        return Err(AppError::BadInput(format!(
+            "The title is {length} characters long, which isn't between {} and {}",
+            options.min_title_length, options.max_title_length
+        )));
+    }
+    Ok(title.to_owned())
+}
+
+fn check_link(link: &str, schemes: &[String]) -> Result<(), AppError> {
+    let url = urlparse(link);
+    if url.hostname.is_none() {
+        return Err(AppError::BadInput(format!(
+            "'{link}' isn't a URL with a host"
+        )));
+    }
+    if !schemes
+        .iter()
+        .any(|scheme| scheme.eq_ignore_ascii_case(&url.scheme))
+    {
+        return Err(AppError::BadInput(format!(
+            "'{link}' has the scheme '{}', which isn't one of {schemes:?}",
+            url.scheme
+        )));
+    }
+    Ok(())
+}
+
+// (Re-)indexes an article by when it was posted and by its score, for workers that may retry or
+// replay this after the fact. `ZADD NX` means an article's `time:` is never changed once it's set, and
+// `ZADD GT` means a stale score can't overwrite a newer (higher) one.
+pub fn index_article(
+    conn: &mut impl ConnectionLike,
+    article: &str,
+    time: u128,
+    score: f64,
+) -> Result<(), Box<dyn Error>> {
+    redis::pipe()
+        .cmd("ZADD")
+        .arg("time:")
+        .arg("NX")
+        .arg(time as f64)
+        .arg(article)
+        .ignore()
+        .cmd("ZADD")
+        .arg("score:")
+        .arg("GT")
+        .arg(score)
+        .arg(article)
+        .ignore()
+        .query(conn)?;
+    Ok(())
+}
+
+pub fn get_article<S>(
+    conn: &mut impl ConnectionLike,
+    article_id: S,
+) -> Result<Option<Article>, Box<dyn Error>>
+where
+    S: Into<String>,
+{
+    let article_id = article_id.into();
+    let mut article = "article:".to_owned();
+    article.push_str(&article_id);
+
+    // HGETALL on a key that doesn't exist gives back an empty HASH rather than nil
+    let hash: HashMap<String, String> = conn.hgetall(&article)?;
+    if hash.is_empty() {
+        return Ok(None);
+    }
+    // An article can be listed by `time:` or a group without being in `score:` (i.e. one that was
+    // imported or is partway through being deleted), so a missing score is read as 0 rather than
+    // failing the whole lookup
+    let score: Option<f64> = conn.zscore("score:", &article)?;
+    Ok(Some(Article::from_hash(
+        article_id,
+        hash,
+        score.unwrap_or_default(),
+    )?))
+}
+
+// Looks up many articles by id at once, i.e. the results of a search, in a single round-trip. The
+// articles come back in the same order as `ids`, with None for any that don't exist.
+pub fn get_articles_by_ids(
+    conn: &mut impl ConnectionLike,
+    ids: &[ArticleId],
+) -> Result<Vec<Option<Article>>, Box<dyn Error>> {
+    if ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut pipe = redis::pipe();
+    for id in ids {
+        let mut article = "article:".to_owned();
+        article.push_str(&id.to_string());
+        pipe.hgetall(&article).zscore("score:", &article);
+    }
+    let replies: Vec<(HashMap<String, String>, Option<f64>)> = pipe.query(conn)?;
+
+    let mut articles = vec![];
+    for (id, (hash, score)) in ids.iter().zip(replies) {
+        // HGETALL on a key that doesn't exist gives back an empty HASH rather than nil
+        if hash.is_empty() {
+            articles.push(None);
+            continue;
+        }
+        let score = score.unwrap_or_default();
+        articles.push(Some(Article::from_hash(id.to_string(), hash, score)?));
+    }
+    Ok(articles)
+}
+
+pub fn get_articles(
+    conn: &mut impl ConnectionLike,
+    page: isize,
+    order: Option<String>,
+) -> Result<Vec<Article>, Box<dyn Error>> {
+    get_articles_with_direction(conn, page, order, SortDirection::Desc)
+}
+
+// Which end of a ranking a listing starts from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortDirection {
+    // Highest first, i.e. the best scoring or newest articles
+    #[default]
+    Desc,
+    // Lowest first, i.e. the worst scoring or oldest articles
+    Asc,
+}
+
+// Same as `get_articles`, but `direction` picks whether page 1 starts from the top or the bottom of
+// `order`. Pages are counted from whichever end the listing starts at, so unless the number of
+// articles is a multiple of ARTICLES_PER_PAGE, a page in one direction straddles two pages in the
+// other. This is a function of its own, rather than a `direction` parameter on `get_articles`, so that
+// the existing callers (and the book's listings they follow) keep their signature.
+pub fn get_articles_with_direction(
+    conn: &mut impl ConnectionLike,
+    page: isize,
+    order: Option<String>,
+    direction: SortDirection,
+) -> Result<Vec<Article>, Box<dyn Error>> {
+    let start = (page - 1) * ARTICLES_PER_PAGE;
+    let end = start + ARTICLES_PER_PAGE - 1;
+
+    let order = order.unwrap_or_else(|| "score:".to_owned());
+    let ids: Vec<String> = match direction {
+        SortDirection::Desc => conn.zrevrange(&order, start, end)?,
+        SortDirection::Asc => conn.zrange(&order, start, end)?,
+    };
+    load_ranked(conn, &order, ids)
+}
+
+// Stable paging for big feeds: rather than a page number, each call is handed the cursor the previous
+// one returned and picks up just below it, so articles being posted or voted on in between can't push
+// others into a skipped or repeated page. Pass `None` to start from the top; the cursor that comes back
+// is `None` once the feed is exhausted. Articles that tie with the last one on a page are skipped,
+// which is rare for `Score` and `Time` (millisecond timestamps) but common for `Votes`.
+pub fn get_articles_after(
+    conn: &mut impl ConnectionLike,
+    order: ArticleOrder,
+    cursor: Option<f64>,
+    limit: usize,
+) -> Result<(Vec<Article>, Option<f64>), Box<dyn Error>> {
+    // A leading `(` makes the bound exclusive, so the article the cursor came from isn't repeated
+    let max = match cursor {
+        Some(cursor) => format!("({cursor}"),
+        None => "+inf".to_owned(),
+    };
+    let ranked: Vec<(String, f64)> =
+        conn.zrevrangebyscore_limit_withscores(order.key(), max, "-inf", 0, limit as isize)?;
+    let next = match ranked.last() {
+        Some((_, score)) if ranked.len() == limit => Some(*score),
+        _ => None,
+    };
+    let ids = ranked.into_iter().map(|(key, _)| key).collect();
+    Ok((load_ranked(conn, order.key(), ids)?, next))
+}
+
+// A feed ranked by `score_weight * votes + time_weight * time`, worked out when it's asked for rather
+// than baked into `score:` when articles are posted and voted on, so the formula can be tuned freely.
+// The blended ranking is built, paged and thrown away in one MULTI/EXEC, so concurrent calls can't
+// see each other's rankings even though they share a temporary key.
+pub fn get_blended_articles(
+    conn: &mut impl ConnectionLike,
+    score_weight: f64,
+    time_weight: f64,
+    page: isize,
+) -> Result<Vec<Article>, Box<dyn Error>> {
+    let start = (page - 1) * ARTICLES_PER_PAGE;
+    let end = start + ARTICLES_PER_PAGE - 1;
+
+    let blended = "blended:";
+    let (ids,): (Vec<String>,) = redis::pipe()
+        .atomic()
+        .cmd("ZUNIONSTORE")
+        .arg(blended)
+        .arg(2)
+        .arg(ArticleOrder::Votes.key())
+        .arg(ArticleOrder::Time.key())
+        .arg("WEIGHTS")
+        .arg(score_weight)
+        .arg(time_weight)
+        .ignore()
+        .zrevrange(blended, start, end)
+        .del(blended)
+        .ignore()
+        .query(conn)?;
+    load_ranked(conn, blended, ids)
+}
+
+// Loads the articles `ids` (in order) from the `order` ranking they were read from. Hidden articles
+// are left out, so a page with any on it comes back short rather than being topped up. Whether each
+// article is hidden is read in the same pipeline as its HASH and score, so it's one round-trip for
+// the whole page.
+fn load_ranked(
+    conn: &mut impl ConnectionLike,
+    order: &str,
+    ids: Vec<String>,
+) -> Result<Vec<Article>, Box<dyn Error>> {
+    if ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut pipe = redis::pipe();
+    for key in &ids {
+        pipe.sismember(HIDDEN, key)
+            .hgetall(key)
+            .zscore("score:", key);
+    }
+    let replies: Vec<(bool, HashMap<String, String>, Option<f64>)> = pipe.query(conn)?;
+
+    let mut articles = vec![];
+    for (key, (hidden, hash, score)) in ids.into_iter().zip(replies) {
+        if hidden {
+            continue;
+        }
+        // An article whose HASH is gone but is still ranked (i.e. after a crash partway through
+        // deleting it) is skipped, and dropped from the rankings (its groups' included) so it doesn't
+        // come up again
+        if hash.is_empty() {
+            let groups: Vec<String> =
+                conn.smembers(article_groups_key(key.trim_start_matches("article:")))?;
+            let mut pipe = redis::pipe();
+            pipe.zrem(order, &key).ignore();
+            for ranking in [ArticleOrder::Score, ArticleOrder::Time, ArticleOrder::Votes] {
+                pipe.zrem(ranking.key(), &key).ignore();
+            }
+            for group in &groups {
+                pipe.zrem(group_score_key(group), &key).ignore();
+            }
+            pipe.query(conn)?;
+            continue;
+        }
+        let id = key.trim_start_matches("article:").to_owned();
+        articles.push(Article::from_hash(id, hash, score.unwrap_or_default())?);
+    }
+    Ok(articles)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AddRemoveResult {
+    pub added: usize,
+    pub removed: usize,
+}
+
+pub fn add_remove_groups<Q, R, S>(
+    conn: &mut impl ConnectionLike,
+    article_id: Q,
+    to_add: Vec<R>,
+    to_remove: Vec<S>,
+) -> Result<AddRemoveResult, Box<dyn Error>>
+where
+    Q: AsRef<str>,
+    R: AsRef<str>,
+    S: AsRef<str>,
+{
+    let article_id = article_id.as_ref();
+    let mut article = "article:".to_owned();
+    article.push_str(article_id);
+    let article_groups = article_groups_key(article_id);
+
+    // SADD and SREM reply with how many members were actually added/removed, so an article that's
+    // already in (or already out of) a group doesn't count towards the result
+    let mut result = AddRemoveResult::default();
+    for group_label in to_add {
+        let group_label = group_label.as_ref();
+        let mut group = "group:".to_owned();
+        group.push_str(group_label);
+        let added: usize = conn.sadd(group, &article)?;
+        conn.sadd("groups:", group_label)?;
+        conn.sadd(&article_groups, group_label)?;
+        if added > 0 {
+            invalidate_group_cache(conn, group_label)?;
+        }
+        result.added += added;
+    }
+
+    for group_label in to_remove {
+        let group_label = group_label.as_ref();
+        let mut group = "group:".to_owned();
+        group.push_str(group_label);
+        let removed: usize = conn.srem(group, &article)?;
+        conn.srem(&article_groups, group_label)?;
+        if removed > 0 {
+            // Its votes within the group go with it, so it doesn't come back with them if it's re-added
+            conn.zrem(group_score_key(group_label), &article)?;
+            conn.del(group_voted_key(group_label, article_id))?;
+            invalidate_group_cache(conn, group_label)?;
+        }
+        result.removed += removed;
+    }
+    Ok(result)
+}
+
+// The inverse of `add_remove_groups`: adds many articles to a single group with one SADD. Returns
+// how many of them weren't already in the group.
+pub fn add_articles_to_group(
+    conn: &mut impl ConnectionLike,
+    group_label: &str,
+    article_ids: &[&str],
+) -> Result<usize, Box<dyn Error>> {
+    if article_ids.is_empty() {
+        return Ok(0);
+    }
+    let articles = article_ids
+        .iter()
+        .map(|id| {
+            let mut article = "article:".to_owned();
+            article.push_str(id);
+            article
+        })
+        .collect::<Vec<String>>();
+
+    let mut group = "group:".to_owned();
+    group.push_str(group_label);
+    let added: usize = conn.sadd(group, &articles)?;
+    conn.sadd("groups:", group_label)?;
+    let mut pipe = redis::pipe();
+    for id in article_ids {
+        pipe.sadd(article_groups_key(id), group_label).ignore();
+    }
+    pipe.query(conn)?;
+    if added > 0 {
+        invalidate_group_cache(conn, group_label)?;
+    }
+    Ok(added)
+}
+
+// The reverse of `group:<label>`: the SET of labels of every group an article is in, kept up to date
+// by posting with tags, `add_remove_groups` and `add_articles_to_group`. Articles grouped before it
+// was added don't have one, so they come back without any groups.
+fn article_groups_key(article_id: &str) -> String {
+    let mut key = "article_groups:".to_owned();
+    key.push_str(article_id);
+    key
+}
+
+// A page of `get_articles`, each with the (sorted) labels of the groups it's in, read from the
+// `article_groups:<id>` reverse index in a single pipeline rather than a query per article.
+pub fn get_articles_with_groups(
+    conn: &mut impl ConnectionLike,
+    page: isize,
+    order: Option<String>,
+) -> Result<Vec<(Article, Vec<String>)>, Box<dyn Error>> {
+    let articles = get_articles(conn, page, order)?;
+    if articles.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut pipe = redis::pipe();
+    for article in &articles {
+        pipe.smembers(article_groups_key(&article.id));
+    }
+    let groups: Vec<Vec<String>> = pipe.query(conn)?;
+    Ok(articles
+        .into_iter()
+        .zip(groups)
+        .map(|(article, mut labels)| {
+            labels.sort();
+            (article, labels)
+        })
+        .collect())
+}
+
+// Every group any article has been added to (by `add_remove_groups`, `add_articles_to_group` or
+// `post_article_tagged`), whether or not the group still has any articles in it.
+pub fn list_groups(conn: &mut impl ConnectionLike) -> Result<Vec<String>, Box<dyn Error>> {
+    Ok(conn.smembers("groups:")?)
+}
+
+pub fn group_size<S>(conn: &mut impl ConnectionLike, label: S) -> Result<usize, Box<dyn Error>>
+where
+    S: AsRef<str>,
+{
+    let mut group = "group:".to_owned();
+    group.push_str(label.as_ref());
+    Ok(conn.scard(group)?)
+}
+
+// The key the intersection of `group` with the `order` ZSET is cached under. It gets its own
+// namespace so that it can't collide with the order ZSETs themselves (the old `order + group` key
+// meant that ordering an unnamed group by `time:` would overwrite `time:`).
+fn group_cache_key(order: &str, group: &str) -> String {
+    let mut key = "groupcache:".to_owned();
+    key.push_str(order);
+    key.push(':');
+    key.push_str(group);
+    key
+}
+
+// The SET of every cached intersection (see `group_cache_key`) that was computed from a group, so that
+// they can all be thrown away as soon as the group changes rather than going stale for up to a minute
+fn group_cache_index(group: &str) -> String {
+    let mut key = "groupcache_index:".to_owned();
+    key.push_str(group);
+    key
+}
+
+fn invalidate_group_cache(
+    conn: &mut impl ConnectionLike,
+    group: &str,
+) -> Result<(), Box<dyn Error>> {
+    let index = group_cache_index(group);
+    let cached: Vec<String> = conn.smembers(&index)?;
+    if !cached.is_empty() {
+        conn.del(&cached)?;
+    }
+    conn.del(&index)?;
+    Ok(())
+}
+
+pub fn get_group_articles<S>(
+    conn: &mut impl ConnectionLike,
+    group: S,
+    page: isize,
+    order: Option<String>,
+) -> Result<Vec<Article>, Box<dyn Error>>
+where
+    S: AsRef<str>,
+{
+    Ok(get_group_articles_ex(conn, group, page, order, false)?.articles)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupArticles {
+    pub articles: Vec<Article>,
+    // Whether the page came from the (up to 60 second old) cached intersection rather than one
+    // computed for this call
+    pub cached: bool,
+}
+
+// Same as `get_group_articles`, but `force_refresh` throws away the cached intersection of the group
+// and the order so that recent changes to either show up immediately.
+pub fn get_group_articles_ex<S>(
+    conn: &mut impl ConnectionLike,
+    group: S,
+    page: isize,
+    order: Option<String>,
+    force_refresh: bool,
+) -> Result<GroupArticles, Box<dyn Error>>
+where
+    S: AsRef<str>,
+{
+    let group = group.as_ref();
+    let order = parse_order(order)?;
+    let key = group_cache_key(order.key(), group);
+    if force_refresh {
+        conn.del(&key)?;
+    }
+    let cached = conn.exists(&key)?;
+    if !cached {
+        let mut group_key = "group:".to_owned();
+        group_key.push_str(group);
+        let size: usize = conn.scard(&group_key)?;
+        if size <= SMALL_GROUP_SIZE {
+            let ids = rank_small_group(conn, group, &key, order, page)?;
+            return Ok(GroupArticles {
+                articles: load_ranked(conn, &key, ids)?,
+                cached,
+            });
+        }
+        intersect_group(conn, group, &key, order)?;
+    }
+    Ok(GroupArticles {
+        articles: get_articles(conn, page, Some(key))?,
+        cached,
+    })
+}
+
+// Caches the intersection of `group` with `order` under `key` (see `group_cache_key`) for a minute
+fn intersect_group(
+    conn: &mut impl ConnectionLike,
+    group: &str,
+    key: &str,
+    order: ArticleOrder,
+) -> Result<(), Box<dyn Error>> {
+    let mut group_key = "group:".to_owned();
+    group_key.push_str(group);
+    conn.zinterstore_max(key, &[group_key.as_str(), order.key()])?;
+    conn.expire(key, 60)?;
+    let index = group_cache_index(group);
+    conn.sadd(&index, key)?;
+    conn.expire(&index, 60)?;
+    Ok(())
+}
+
+// Ranks a small group by looking its articles up in `order` one by one and sorting them here, rather
+// than intersecting it with the whole of `order`, and returns the ids of the articles on `page`. The
+// ranking is still cached under `key` so that later pages (and `get_group_page`'s total) can use it
+// the same way as an intersection. Articles are ranked exactly as `intersect_group`'s ZINTERSTORE and
+// a ZREVRANGE would: by the larger of their score and the 1 a SET member counts as, then by id in
+// reverse.
+fn rank_small_group(
+    conn: &mut impl ConnectionLike,
+    group: &str,
+    key: &str,
+    order: ArticleOrder,
+    page: isize,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut group_key = "group:".to_owned();
+    group_key.push_str(group);
+    let members: Vec<String> = conn.smembers(&group_key)?;
+    let mut pipe = redis::pipe();
+    for member in &members {
+        pipe.zscore(order.key(), member);
+    }
+    let scores: Vec<Option<f64>> = pipe.query(conn)?;
+    let mut ranked = members
+        .into_iter()
+        .zip(scores)
+        .filter_map(|(member, score)| score.map(|score| (score.max(1.0), member)))
+        .collect::<Vec<(f64, String)>>();
+    ranked.sort_by(|(a_score, a), (b_score, b)| b_score.total_cmp(a_score).then_with(|| b.cmp(a)));
+
+    let index = group_cache_index(group);
+    let mut pipe = redis::pipe();
+    pipe.del(key).ignore();
+    if !ranked.is_empty() {
+        pipe.zadd_multiple(key, &ranked)
+            .ignore()
+            .expire(key, 60)
+            .ignore()
+            .sadd(&index, key)
+            .ignore()
+            .expire(&index, 60)
+            .ignore();
+    }
+    pipe.query(conn)?;
+
+    let start = match usize::try_from((page - 1) * ARTICLES_PER_PAGE) {
+        Ok(start) => start,
+        Err(_) => return Ok(vec![]),
+    };
+    Ok(ranked
+        .into_iter()
+        .skip(start)
+        .take(ARTICLES_PER_PAGE as usize)
+        .map(|(_, member)| member)
+        .collect())
+}
+
+// Records a vote within a group, unless `user` already voted for `article` in it. It's appended to
+// VOTE_SCRIPT wrapped in a `global_vote` function (see `article_vote_in_group`), so KEYS[1..7] and
+// ARGV[1..8] are VOTE_SCRIPT's and the global vote happens in the same script as the group one. After
+// those, KEYS[8] is the group's SET, which the article has to be in (or else -1 comes back and neither
+// vote is cast), then its `group_voted:` SET, `group_score:` ZSET and the group's cached ranking,
+// which is now out of date. ARGV[9] is how long the `group_voted:` SET is kept. A user the global
+// vote turned away for having voted against the article (in KEYS[7]) doesn't get a group vote either.
+const GROUP_VOTE_SCRIPT: &str = r"
+if redis.call('SISMEMBER', KEYS[8], KEYS[4]) == 0 then
+    return -1
+end
+if global_vote() == 0 and redis.call('SISMEMBER', KEYS[7], ARGV[1]) == 1 then
+    return 0
+end
+if redis.call('SADD', KEYS[9], ARGV[1]) == 0 then
+    return 0
+end
+redis.call('EXPIRE', KEYS[9], ARGV[9])
+redis.call('ZINCRBY', KEYS[10], 1, KEYS[4])
+redis.call('DEL', KEYS[11])
+return 1
+";
+
+// Votes for `article` on behalf of `group`'s community: besides the usual global vote, the article
+// gets a point in `group_score:<group>`, which `get_group_articles_by_group_votes` ranks by. Each user
+// gets one vote per group, tracked separately from their global vote, so a user who already voted for
+// the article elsewhere can still vote for it in the group (without it counting twice globally), but
+// one who voted against it can't. Both votes are cast by one script, so the article can't leave the
+// group between them. Returns whether the group vote was counted. Voting for an article that isn't in the group is an `AppError::BadInput`.
+pub fn article_vote_in_group<S>(
+    conn: &mut impl ConnectionLike,
+    user: S,
+    article: S,
+    group: S,
+) -> Result<bool, Box<dyn Error>>
+where
+    S: AsRef<str>,
+{
+    let (user, article, group) = (user.as_ref(), article.as_ref(), group.as_ref());
+    let article_id = scheme_article_id(&DefaultScheme, article)?;
+    check_voting_window(conn, &DefaultScheme, article, ClockSource::Local)?;
+    let mut group_key = "group:".to_owned();
+    group_key.push_str(group);
+
+    // Hashing the combined script once, rather than on every vote
+    static SCRIPT: OnceLock<redis::Script> = OnceLock::new();
+    let script = SCRIPT.get_or_init(|| {
+        redis::Script::new(&format!(
+            "local function global_vote(){VOTE_SCRIPT}end{GROUP_VOTE_SCRIPT}"
+        ))
+    });
+    let mut invocation = script.prepare_invoke();
+    add_vote_keys_and_args(&mut invocation, &DefaultScheme, user, article, 1)?;
+    let counted: i64 = invocation
+        .key(&group_key)
+        .key(group_voted_key(group, article_id))
+        .key(group_score_key(group))
+        .key(group_cache_key(GROUP_SCORE, group))
+        .arg(ONE_WEEK_IN_SECONDS)
+        .invoke(conn)?;
+    match counted {
+        -1 => Err(AppError::BadInput(format!("{article} isn't in the group '{group}'")).into()),
+        counted => Ok(counted == 1),
+    }
+}
+
+// Same as `get_group_articles`, but ranked by the votes cast within the group (see
+// `article_vote_in_group`) rather than by global score. Articles nobody has voted for in the group
+// yet are still listed, after the rest.
+pub fn get_group_articles_by_group_votes<S>(
+    conn: &mut impl ConnectionLike,
+    group: S,
+    page: isize,
+) -> Result<Vec<Article>, Box<dyn Error>>
+where
+    S: AsRef<str>,
+{
+    let group = group.as_ref();
+    let key = group_cache_key(GROUP_SCORE, group);
+    let cached: bool = conn.exists(&key)?;
+    if !cached {
+        let mut group_key = "group:".to_owned();
+        group_key.push_str(group);
+        // The group's SET is weighted 0 so that its members are all there, but only the group votes
+        // count towards their scores
+        redis::cmd("ZUNIONSTORE")
+            .arg(&key)
+            .arg(2)
+            .arg(&group_key)
+            .arg(group_score_key(group))
+            .arg("WEIGHTS")
+            .arg(0)
+            .arg(1)
+            .query(conn)?;
+        conn.expire(&key, 60)?;
+        let index = group_cache_index(group);
+        conn.sadd(&index, &key)?;
+        conn.expire(&index, 60)?;
+    }
+    get_articles(conn, page, Some(key))
+}
+
+fn group_score_key(group: &str) -> String {
+    let mut key = GROUP_SCORE.to_owned();
+    key.push_str(group);
+    key
+}
+
+fn group_voted_key(group: &str, article_id: &str) -> String {
+    let mut key = "group_voted:".to_owned();
+    key.push_str(group);
+    key.push(':');
+    key.push_str(article_id);
+    key
+}
+
+// The top `limit` articles of each of `groups` by `order`, i.e. for a dashboard with a column per group.
+// The intersections are cached and shared with `get_group_articles`, and rather than a call per group
+// it's one round-trip to see which are cached, one to build the rest and read every group's top ids,
+// and one to load all of the articles.
+pub fn top_per_group(
+    conn: &mut impl ConnectionLike,
+    groups: &[&str],
+    limit: isize,
+    order: Option<String>,
+) -> Result<HashMap<String, Vec<Article>>, Box<dyn Error>> {
+    if groups.is_empty() || limit <= 0 {
+        return Ok(groups
+            .iter()
+            .map(|group| (group.to_string(), vec![]))
+            .collect());
+    }
+    let order = parse_order(order)?;
+    let keys = groups
+        .iter()
+        .map(|group| group_cache_key(order.key(), group))
+        .collect::<Vec<_>>();
+
+    let mut pipe = redis::pipe();
+    for key in &keys {
+        pipe.exists(key);
+    }
+    let cached: Vec<bool> = pipe.query(conn)?;
+
+    let mut pipe = redis::pipe();
+    for ((group, key), cached) in groups.iter().zip(&keys).zip(cached) {
+        if !cached {
+            let mut group_key = "group:".to_owned();
+            group_key.push_str(group);
+            let index = group_cache_index(group);
+            pipe.zinterstore_max(key, &[group_key.as_str(), order.key()])
+                .ignore()
+                .expire(key, 60)
+                .ignore()
+                .sadd(&index, key)
+                .ignore()
+                .expire(&index, 60)
+                .ignore();
+        }
+        pipe.zrevrange(key, 0, limit - 1);
+    }
+    let ranked: Vec<Vec<String>> = pipe.query(conn)?;
+    let hidden = hidden_among(conn, &ranked.concat())?;
+    let ranked = ranked
+        .into_iter()
+        .map(|keys| {
+            keys.into_iter()
+                .filter(|key| !hidden.contains(key))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let ids = ranked
+        .iter()
+        .flatten()
+        .map(|key| key.trim_start_matches("article:").parse())
+        .collect::<Result<Vec<ArticleId>, _>>()?;
+    let mut articles = get_articles_by_ids(conn, &ids)?.into_iter();
+    let mut top = HashMap::new();
+    for (group, ranked) in groups.iter().zip(ranked) {
+        // Articles that have gone missing since they were ranked are left out
+        let group_articles = articles.by_ref().take(ranked.len()).flatten().collect();
+        top.insert(group.to_string(), group_articles);
+    }
+    Ok(top)
+}
+
+// The order a group's articles are ranked by, which defaults to `score:`
+fn parse_order(order: Option<String>) -> Result<ArticleOrder, Box<dyn Error>> {
+    match order {
+        Some(order) => Ok(order.parse()?),
+        None => Ok(ArticleOrder::Score),
+    }
+}
+
+// One page of a longer listing, along with enough about the rest of it to render pagination
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub page: isize,
+    // How many items there are across every page. For articles, that includes any hidden ones, which
+    // are still ranked but left out of the pages, so a page can come back short of ARTICLES_PER_PAGE
+    // and `total` can be a little more than a client paging through would actually see.
+    pub total: usize,
+    pub has_next: bool,
+    // Whether the page is past the end of the listing (or before its start), as opposed to being empty
+    // because the listing is. The first page of an empty listing is just empty, so a client walking
+    // pages until `has_next` is false never sees this, but one that jumps ahead can tell it went too far.
+    pub out_of_range: bool,
+}
+
+impl<T> Page<T> {
+    fn new(items: Vec<T>, page: isize, total: usize) -> Page<T> {
+        Page {
+            items,
+            page,
+            total,
+            has_next: page * ARTICLES_PER_PAGE < total as isize,
+            out_of_range: page < 1
+                || (page > 1 && (page - 1) * ARTICLES_PER_PAGE >= total as isize),
+        }
+    }
+}
+
+// Same as `get_articles`, but with how many articles there are in `order`, so that clients know when
+// to stop paging. Pages before the first are out of range and empty, rather than wrapping around to
+// the end of `order` like a negative ZREVRANGE index would.
+pub fn get_articles_page(
+    conn: &mut impl ConnectionLike,
+    page: isize,
+    order: Option<String>,
+) -> Result<Page<Article>, Box<dyn Error>> {
+    let order = order.unwrap_or_else(|| "score:".to_owned());
+    let total: usize = conn.zcard(&order)?;
+    if page < 1 {
+        return Ok(Page::new(vec![], page, total));
+    }
+    let articles = get_articles(conn, page, Some(order))?;
+    Ok(Page::new(articles, page, total))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupResult {
+    // Whether `group:<label>` has any articles in it; an empty group and one that was never created
+    // are indistinguishable in Redis, so both are reported as not existing
+    pub exists: bool,
+    pub page: Page<Article>,
+}
+
+// Same as `get_group_articles`, but says whether the group exists at all and how many articles it has,
+// rather than just handing back a (possibly empty) page.
+pub fn get_group_page<S>(
+    conn: &mut impl ConnectionLike,
+    group: S,
+    page: isize,
+    order: Option<String>,
+) -> Result<GroupResult, Box<dyn Error>>
+where
+    S: AsRef<str>,
+{
+    let group = group.as_ref();
+    let order = parse_order(order)?;
+    let mut group_key = "group:".to_owned();
+    group_key.push_str(group);
+    let exists: bool = conn.exists(&group_key)?;
+    if !exists {
+        return Ok(GroupResult {
+            exists,
+            page: Page::new(vec![], page, 0),
+        });
+    }
+
+    let order = order.key().to_owned();
+    let articles = get_group_articles_ex(conn, group, page, Some(order.clone()), false)?;
+    let total: usize = conn.zcard(group_cache_key(&order, group))?;
+    Ok(GroupResult {
+        exists,
+        page: Page::new(articles.articles, page, total),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    // One JSON object per line
+    JsonLines,
+    // A header row followed by one row per article
+    Csv,
+}
+
+// The columns of an export, sorted so that the output is deterministic
+const EXPORT_FIELDS: [&str; 7] = ["id", "link", "poster", "score", "time", "title", "votes"];
+
+// Writes every article to `writer`, oldest first, returning how many there were. Articles are walked
+// through `time:` and written a batch at a time rather than loaded up front, so that exporting doesn't
+// need the whole dataset (or even all of its ids) to fit in memory. Articles posted during the export
+// are appended to `time:` and so are exported too, but one deleted during it can shift a later one
+// into an already exported batch, which is then missed.
+pub fn export_articles(
+    conn: &mut impl ConnectionLike,
+    mut writer: impl Write,
+    format: ExportFormat,
+) -> Result<usize, Box<dyn Error>> {
+    if format == ExportFormat::Csv {
+        writeln!(writer, "{}", EXPORT_FIELDS.join(","))?;
+    }
+
+    let mut exported = 0;
+    let mut start = 0;
+    loop {
+        let keys: Vec<String> = conn.zrange("time:", start, start + 99)?;
+        if keys.is_empty() {
+            break;
+        }
+        start += keys.len() as isize;
+
+        let mut pipe = redis::pipe();
+        for key in &keys {
+            pipe.hgetall(key).zscore("score:", key);
+        }
+        let articles: Vec<(BTreeMap<String, String>, Option<f64>)> = pipe.query(conn)?;
+
+        for (key, (mut fields, score)) in keys.iter().zip(articles) {
+            if fields.is_empty() {
+                continue;
+            }
+            fields.insert(
+                "id".to_owned(),
+                key.trim_start_matches("article:").to_owned(),
+            );
+            fields.insert("score".to_owned(), score.unwrap_or_default().to_string());
+
+            match format {
+                ExportFormat::JsonLines => {
+                    serde_json::to_writer(&mut writer, &fields)?;
+                    writeln!(writer)?;
+                }
+                ExportFormat::Csv => {
+                    let row = EXPORT_FIELDS
+                        .iter()
+                        .map(|field| csv_field(fields.get(*field).map_or("", String::as_str)))
+                        .collect::<Vec<String>>();
+                    writeln!(writer, "{}", row.join(","))?;
+                }
+            }
+            exported += 1;
+        }
+    }
+    writer.flush()?;
+    Ok(exported)
+}
+
+// Quotes a CSV field if it has anything in it that would otherwise be mistaken for CSV syntax
+fn csv_field(value: &str) -> String {
+    if value.contains(|c: char| matches!(c, ',' | '"' | '\n' | '\r')) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+// Moves the `article:` counter up to at least ARGV[1], without ever moving it back down
+const ADVANCE_COUNTER_SCRIPT: &str = r"
+local current = tonumber(redis.call('GET', KEYS[1]) or 0)
+if current < tonumber(ARGV[1]) then
+    redis.call('SET', KEYS[1], ARGV[1])
+end
+";
+
+// Restores articles written by `export_articles`, returning how many were imported. Articles that
+// already exist are overwritten if `overwrite` is set and skipped otherwise. The `article:` counter is
+// moved past the largest imported id so that new posts don't collide with imported ones. Who voted for
+// what isn't part of an export, so imported articles can be voted for again by anyone.
+pub fn import_articles(
+    conn: &mut impl ConnectionLike,
+    reader: impl Read,
+    format: ExportFormat,
+    overwrite: bool,
+) -> Result<usize, Box<dyn Error>> {
+    let mut lines = BufReader::new(reader).lines();
+    let header = match format {
+        ExportFormat::JsonLines => vec![],
+        ExportFormat::Csv => match read_csv_record(&mut lines)? {
+            Some(header) => header,
+            None => return Ok(0),
+        },
+    };
+
+    let mut imported = 0;
+    let mut max_id = 0;
+    loop {
+        let fields: BTreeMap<String, String> = match format {
+            ExportFormat::JsonLines => match lines.next() {
+                Some(line) => {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    serde_json::from_str(&line)?
+                }
+                None => break,
+            },
+            ExportFormat::Csv => match read_csv_record(&mut lines)? {
+                Some(row) => header.iter().cloned().zip(row).collect(),
+                None => break,
+            },
+        };
+
+        let field = |name: &str| {
+            fields
+                .get(name)
+                .cloned()
+                .ok_or_else(|| format!("An imported article is missing its '{name}' field"))
+        };
+        let id: ArticleId = field("id")?.parse()?;
+        let score: f64 = field("score")?.parse()?;
+        let time: f64 = field("time")?.parse()?;
+        let votes: usize = field("votes")?.parse()?;
+        let mut hash = vec![
+            ("title".to_owned(), field("title")?),
+            ("link".to_owned(), field("link")?),
+            ("poster".to_owned(), field("poster")?),
+            ("time".to_owned(), field("time")?),
+            ("votes".to_owned(), field("votes")?),
+        ];
+        // Anything else is the article's extra metadata, which only JSON Lines exports carry
+        hash.extend(
+            fields
+                .iter()
+                .filter(|(name, _)| !RESERVED_FIELDS.contains(&name.as_str()))
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+        max_id = cmp::max(max_id, id.0);
+
+        let mut article = "article:".to_owned();
+        article.push_str(&id.to_string());
+        if !overwrite && conn.exists(&article)? {
+            continue;
+        }
+        redis::pipe()
+            .atomic()
+            .del(&article)
+            .ignore()
+            .hset_multiple(&article, &hash)
+            .ignore()
+            .zadd("score:", &article, score)
+            .ignore()
+            .zadd("time:", &article, time)
+            .ignore()
+            .zadd("votes:", &article, votes)
+            .ignore()
+            .query(conn)?;
+        imported += 1;
+    }
+
+    redis::Script::new(ADVANCE_COUNTER_SCRIPT)
+        .key("article:")
+        .arg(max_id)
+        .invoke(conn)?;
+    Ok(imported)
+}
+
+// Reads one CSV record, which may span several lines if a quoted field has a newline in it
+fn read_csv_record(
+    lines: &mut impl Iterator<Item = std::io::Result<String>>,
+) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+    let mut record = match lines.next() {
+        Some(line) => line?,
+        None => return Ok(None),
+    };
+    // An odd number of quotes means a quoted field is still open
+    while record.matches('"').count() % 2 == 1 {
+        match lines.next() {
+            Some(line) => {
+                record.push('\n');
+                record.push_str(&line?);
+            }
+            None => return Err("A CSV record ended partway through a quoted field".into()),
+        }
+    }
+
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = record.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    Ok(Some(fields))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cmp::Ordering,
+        collections::HashMap,
+        error::Error,
+        thread,
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    };
+
+    use redis::{Commands, Connection, ConnectionLike, ErrorKind, RedisError, RedisResult, Value};
+    use test_support::unpack_commands;
+
+    #[cfg(feature = "vote-log")]
+    use crate::read_vote_events;
+    use crate::{
+        add_articles_to_group, add_remove_groups, article_downvote, article_downvote_with_scheme,
+        article_unvote, article_unvote_with_scheme, article_vote, article_vote_in_group,
+        article_vote_weighted, article_vote_with_clock, article_vote_with_scheme, articles_to_json,
+        csv_field, export_articles, get_article, get_articles, get_articles_after,
+        get_articles_by_ids, get_articles_page, get_articles_with_direction,
+        get_articles_with_groups, get_blended_articles, get_group_articles,
+        get_group_articles_by_group_votes, get_group_articles_ex, get_group_page, group_cache_key,
+        group_size, import_articles, index_article, intersect_group, list_groups, post_article,
+        post_article_full, post_article_scored, post_article_tagged, post_article_with,
+        post_article_with_clock, post_article_with_extra, read_csv_record, sort_by_score,
+        top_per_group, unhide_article, with_retry, AppError, Article, ArticleId, ArticleOrder,
+        ClientError, ClockSource, DefaultScheme, ExportFormat, KeyScheme, Namespace, PostOptions,
+        SortDirection, ARTICLES_PER_PAGE, GROUP_SCORE, VOTE_CHANNEL, VOTE_SCORE,
+    };
+
+    test_support::test_connections!();
+
+    // Removes everything `post_article` creates for an article so tests don't leave data behind
+    fn delete_article(conn: &mut Connection, article_id: &str) {
+        let mut article = "article:".to_owned();
+        article.push_str(article_id);
+        let mut voted = "voted:".to_owned();
+        voted.push_str(article_id);
+        let mut vote_weights = "vote_weight:".to_owned();
+        vote_weights.push_str(article_id);
+        let mut article_groups = "article_groups:".to_owned();
+        article_groups.push_str(article_id);
+        let mut against = "against:".to_owned();
+        against.push_str(article_id);
+
+        conn.del::<_, usize>(&[&article, &voted, &vote_weights, &article_groups, &against])
+            .unwrap();
+        conn.srem::<_, _, usize>("hidden:", &article).unwrap();
+        conn.zrem::<_, _, usize>("score:", &article).unwrap();
+        conn.zrem::<_, _, usize>("time:", &article).unwrap();
+        conn.zrem::<_, _, usize>("votes:", &article).unwrap();
+    }
+
+    // Copies how `order` scores the articles into `ranking`, a ZSET of the test's own. Other tests
+    // (and whatever else is on the server) can rank any number of articles above a test's fresh ones
+    // in `score:`, `time:` or `votes:`, so a test that needs its articles on page 1 lists `ranking`
+    // instead.
+    fn rank_only(conn: &mut Connection, ranking: &str, order: &str, article_ids: &[&str]) {
+        for article_id in article_ids {
+            let mut article = "article:".to_owned();
+            article.push_str(article_id);
+            let score: f64 = conn.zscore(order, &article).unwrap();
+            conn.zadd::<_, _, _, usize>(ranking, &article, score)
+                .unwrap();
+        }
+    }
+
+    // Execute`cargo test -p ch01 -- --nocapture` to run these tests
+    #[test]
+    fn test_article_functionality() {
+        let (_redis, mut conn) = test_conn();
+
+        let article_id = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+        let mut article = "article:".to_owned();
+        article.push_str(&article_id);
+        println!("We posted a new article with id {article_id} ({article})\n");
+        assert!(!article_id.is_empty());
+
+        println!("Its HASH looks like:");
+        let r: HashMap<String, String> = conn.hgetall(&article).unwrap();
+        println!("{:#?}\n", r);
+        assert!(r.len() != 0);
+
+        article_vote(&mut conn, "other_user", &article).unwrap();
         let v: usize = conn.hget(&article, "votes").unwrap();
         println!("We voted for the article, it now has votes: {v}\n");
         assert!(v > 1);
 
-        println!("The currently highest-scoring articles are:");
-        let articles = get_articles(&mut conn, 1, None).unwrap();
-        for article in &articles {
-            println!("{article:?}");
+        println!("The currently highest-scoring articles are:");
+        let articles = get_articles(&mut conn, 1, None).unwrap();
+        for article in &articles {
+            println!("{article:?}");
+        }
+        println!();
+        assert!(articles.len() >= 1);
+
+        add_remove_groups::<_, _, &str>(&mut conn, article_id, vec!["new-group"], vec![]).unwrap();
+        println!("We added the article to a new group, other articles include:");
+        let articles = get_group_articles(&mut conn, "new-group", 1, None).unwrap();
+        for article in &articles {
+            println!("{article:?}");
+        }
+        println!();
+        assert!(articles.len() >= 1);
+
+        let keys = [
+            "article:*",
+            "group:*",
+            "groups:*",
+            "groupcache:*",
+            "groupcache_index:*",
+            "score:*",
+            "time:*",
+            "vote_weight:*",
+            "voted:*",
+            "votes:*",
+        ];
+        for key in keys {
+            let sub_keys: Vec<String> = conn.keys(key).unwrap();
+            for sub_key in sub_keys {
+                conn.del::<_, usize>(sub_key).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_post_article_full() {
+        let (_redis, mut conn) = test_conn();
+
+        let posted =
+            post_article_full(&mut conn, "username", "A title", "http://google.com").unwrap();
+        println!("We posted a new article and got back:\n{posted:#?}\n");
+        assert_eq!(posted.votes, 1);
+        assert_eq!(posted.poster, "username");
+
+        let fetched = get_article(&mut conn, &posted.id).unwrap();
+        assert_eq!(Some(&posted), fetched.as_ref());
+
+        delete_article(&mut conn, &posted.id);
+    }
+
+    #[test]
+    fn test_get_article() {
+        let (_redis, mut conn) = test_conn();
+
+        let article_id = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+        let article = get_article(&mut conn, &article_id)
+            .unwrap()
+            .expect("The article we just posted should exist");
+        println!("We fetched the article we just posted:\n{article:#?}\n");
+        assert_eq!(article.id, article_id);
+        assert_eq!(article.title, "A title");
+
+        let missing = get_article(&mut conn, "not-an-article").unwrap();
+        println!("Fetching an article that doesn't exist gives us {missing:?}");
+        assert!(missing.is_none());
+
+        delete_article(&mut conn, &article_id);
+    }
+
+    #[test]
+    fn test_add_remove_groups_result() {
+        let (_redis, mut conn) = test_conn();
+
+        let article_id = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+
+        let result = add_remove_groups(
+            &mut conn,
+            &article_id,
+            vec!["result-group"],
+            vec!["other-group"],
+        )
+        .unwrap();
+        println!("Adding to one new group and removing from a group it isn't in: {result:?}\n");
+        assert_eq!((result.added, result.removed), (1, 0));
+
+        let result =
+            add_remove_groups::<_, _, &str>(&mut conn, &article_id, vec!["result-group"], vec![])
+                .unwrap();
+        println!("Adding it to the same group again: {result:?}");
+        assert_eq!((result.added, result.removed), (0, 0));
+
+        delete_article(&mut conn, &article_id);
+        conn.del::<_, usize>("group:result-group").unwrap();
+        conn.srem::<_, _, usize>("groups:", "result-group").unwrap();
+    }
+
+    #[test]
+    fn test_list_groups() {
+        let (_redis, mut conn) = test_conn();
+
+        let first = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+        let second = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+        add_remove_groups::<_, _, &str>(&mut conn, &first, vec!["directory-a"], vec![]).unwrap();
+        add_remove_groups::<_, _, &str>(
+            &mut conn,
+            &second,
+            vec!["directory-a", "directory-b"],
+            vec![],
+        )
+        .unwrap();
+
+        let groups = list_groups(&mut conn).unwrap();
+        println!("The groups we know about are: {groups:?}\n");
+        assert!(groups.contains(&"directory-a".to_owned()));
+        assert!(groups.contains(&"directory-b".to_owned()));
+
+        let size = group_size(&mut conn, "directory-a").unwrap();
+        println!("directory-a has {size} articles in it");
+        assert_eq!(size, 2);
+        assert_eq!(group_size(&mut conn, "directory-b").unwrap(), 1);
+
+        delete_article(&mut conn, &first);
+        delete_article(&mut conn, &second);
+        conn.del::<_, usize>(&["group:directory-a", "group:directory-b"])
+            .unwrap();
+        conn.srem::<_, _, usize>("groups:", &["directory-a", "directory-b"])
+            .unwrap();
+    }
+
+    #[test]
+    fn test_articles_to_json() {
+        let article = Article {
+            id: "1".to_owned(),
+            title: "A title".to_owned(),
+            link: "http://google.com".to_owned(),
+            poster: "username".to_owned(),
+            time: 1655000000000,
+            votes: 3,
+            score: 1655000001296.0,
+            extra: HashMap::new(),
+        };
+
+        let json = articles_to_json(&[article.clone()]);
+        println!("Our article as JSON looks like:\n{json:#}");
+        assert_eq!(
+            json,
+            serde_json::json!([{
+                "id": "1",
+                "title": "A title",
+                "link": "http://google.com",
+                "poster": "username",
+                "time": 1655000000000_u64,
+                "votes": 3,
+                "score": 1655000001296.0,
+            }])
+        );
+
+        let parsed: Vec<Article> = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, vec![article]);
+    }
+
+    #[test]
+    fn test_score_precision() {
+        let (_redis, mut conn) = test_conn();
+
+        let article_id = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+        let mut article = "article:".to_owned();
+        article.push_str(&article_id);
+
+        // The largest multiple of 1000 that an f64 still represents exactly (2^53 is ~9.007e15)
+        let large_score = 9_007_199_254_740_000.0;
+        println!("Let's pretend {article} has a score of {large_score}");
+        conn.zadd::<_, _, _, usize>("score:", &article, large_score)
+            .unwrap();
+
+        article_vote(&mut conn, "other_user", &article).unwrap();
+        let score: f64 = conn.zscore("score:", &article).unwrap();
+        println!("After a vote, its score is {score}");
+        assert_eq!(score, large_score + 432.0);
+        assert_eq!(score as u64, 9_007_199_254_740_432);
+
+        delete_article(&mut conn, &article_id);
+    }
+
+    #[cfg(feature = "vote-log")]
+    #[test]
+    fn test_vote_events() {
+        let (_redis, mut conn) = test_conn();
+        conn.del::<_, usize>("votes:stream").unwrap();
+
+        let article_id = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+        let mut article = "article:".to_owned();
+        article.push_str(&article_id);
+        article_vote(&mut conn, "other_user", &article).unwrap();
+
+        let events = read_vote_events(&mut conn, &DefaultScheme, 10).unwrap();
+        println!("Voting logged these events:\n{events:#?}");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].user, "other_user");
+        assert_eq!(events[0].article, article);
+        assert_eq!(events[0].direction, "up");
+
+        delete_article(&mut conn, &article_id);
+        conn.del::<_, usize>("votes:stream").unwrap();
+    }
+
+    #[test]
+    fn test_get_group_articles_refresh() {
+        let (_redis, mut conn) = test_conn();
+
+        let first = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+        add_remove_groups::<_, _, &str>(&mut conn, &first, vec!["refresh-group"], vec![]).unwrap();
+        let result = get_group_articles_ex(&mut conn, "refresh-group", 1, None, true).unwrap();
+        assert!(!result.cached);
+        assert_eq!(result.articles.len(), 1);
+
+        println!("Adding a second article to the group...");
+        let second = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+        add_remove_groups::<_, _, &str>(&mut conn, &second, vec!["refresh-group"], vec![]).unwrap();
+
+        let changed = get_group_articles_ex(&mut conn, "refresh-group", 1, None, false).unwrap();
+        println!(
+            "Changing the group dropped its cache, so even without a refresh we see {} articles",
+            changed.articles.len()
+        );
+        assert!(!changed.cached);
+        assert_eq!(changed.articles.len(), 2);
+        let cached = get_group_articles_ex(&mut conn, "refresh-group", 1, None, false).unwrap();
+        assert!(cached.cached);
+
+        let fresh = get_group_articles_ex(&mut conn, "refresh-group", 1, None, true).unwrap();
+        println!("Forcing a refresh shows {} articles", fresh.articles.len());
+        assert!(!fresh.cached);
+        assert_eq!(fresh.articles.len(), 2);
+
+        delete_article(&mut conn, &first);
+        delete_article(&mut conn, &second);
+        conn.del::<_, usize>(&[
+            "group:refresh-group",
+            "groupcache:score::refresh-group",
+            "groupcache_index:refresh-group",
+        ])
+        .unwrap();
+        conn.srem::<_, _, usize>("groups:", "refresh-group")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_group_cache_key_collisions() {
+        let (_redis, mut conn) = test_conn();
+
+        let article_id = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+        let other_id = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+        let time_size: usize = conn.zcard("time:").unwrap();
+
+        println!("Let's make groups named after an order and with no name at all");
+        add_remove_groups::<_, _, &str>(&mut conn, &article_id, vec!["time:", ""], vec![]).unwrap();
+        for group in ["time:", ""] {
+            for order in ["score:", "time:"] {
+                let articles =
+                    get_group_articles(&mut conn, group, 1, Some(order.to_owned())).unwrap();
+                assert_eq!(articles.len(), 1);
+            }
+        }
+
+        let time_size_after: usize = conn.zcard("time:").unwrap();
+        println!("time: had {time_size} members before and {time_size_after} after");
+        assert_eq!(time_size, time_size_after);
+
+        delete_article(&mut conn, &article_id);
+        delete_article(&mut conn, &other_id);
+        conn.del::<_, usize>(&["group:time:", "group:"]).unwrap();
+        let cached: Vec<String> = conn.keys("groupcache:*").unwrap();
+        conn.del::<_, usize>(cached).unwrap();
+        conn.srem::<_, _, usize>("groups:", &["time:", ""]).unwrap();
+    }
+
+    #[test]
+    fn test_add_articles_to_group() {
+        let (_redis, mut conn) = test_conn();
+
+        let article_ids = (0..5)
+            .map(|_| {
+                post_article(&mut conn, "username", "A title", "http://google.com")
+                    .unwrap()
+                    .to_string()
+            })
+            .collect::<Vec<String>>();
+        let ids = article_ids.iter().map(|id| id.as_str()).collect::<Vec<_>>();
+
+        let added = add_articles_to_group(&mut conn, "bulk-group", &ids).unwrap();
+        let size: usize = conn.scard("group:bulk-group").unwrap();
+        println!("We added {added} articles to bulk-group, which now has {size} members");
+        assert_eq!(added, 5);
+        assert_eq!(size, 5);
+        assert_eq!(
+            add_articles_to_group(&mut conn, "bulk-group", &ids).unwrap(),
+            0
+        );
+
+        for id in &article_ids {
+            delete_article(&mut conn, id);
+        }
+        conn.del::<_, usize>("group:bulk-group").unwrap();
+        conn.srem::<_, _, usize>("groups:", "bulk-group").unwrap();
+    }
+
+    #[test]
+    fn test_weighted_votes() {
+        let (_redis, mut conn) = test_conn();
+
+        let posted =
+            post_article_full(&mut conn, "username", "A title", "http://google.com").unwrap();
+        let mut article = "article:".to_owned();
+        article.push_str(&posted.id);
+
+        article_vote_weighted(&mut conn, "regular_user", &article, 1).unwrap();
+        article_vote_weighted(&mut conn, "moderator", &article, 3).unwrap();
+        println!("A regular user and a moderator (weight 3) voted for the article");
+        println!("A repeat vote from the moderator shouldn't count");
+        article_vote_weighted(&mut conn, "moderator", &article, 3).unwrap();
+
+        let voted = get_article(&mut conn, &posted.id).unwrap().unwrap();
+        println!(
+            "It now has {} votes and a score of {}",
+            voted.votes, voted.score
+        );
+        assert_eq!(voted.votes, posted.votes + 4);
+        assert_eq!(voted.score, posted.score + 4.0 * 432.0);
+
+        let mut vote_weights = "vote_weight:".to_owned();
+        vote_weights.push_str(&posted.id);
+        let weight: usize = conn.hget(&vote_weights, "moderator").unwrap();
+        assert_eq!(weight, 3);
+
+        delete_article(&mut conn, &posted.id);
+    }
+
+    #[test]
+    fn test_article_unvote() {
+        let (_redis, mut conn) = test_conn();
+
+        let posted =
+            post_article_full(&mut conn, "username", "A title", "http://google.com").unwrap();
+        let mut article = "article:".to_owned();
+        article.push_str(&posted.id);
+
+        article_vote(&mut conn, "other_user", &article).unwrap();
+        article_vote_weighted(&mut conn, "moderator", &article, 3).unwrap();
+        println!("Two users voted, and now they'll take their votes back");
+        assert!(article_unvote(&mut conn, "other_user", &article).unwrap());
+        assert!(article_unvote(&mut conn, "moderator", &article).unwrap());
+        assert!(!article_unvote(&mut conn, "never_voted", &article).unwrap());
+
+        let unvoted = get_article(&mut conn, &posted.id).unwrap().unwrap();
+        println!(
+            "It's back to {} votes and a score of {}",
+            unvoted.votes, unvoted.score
+        );
+        assert_eq!(unvoted.votes, posted.votes);
+        assert_eq!(unvoted.score, posted.score);
+
+        delete_article(&mut conn, &posted.id);
+    }
+
+    #[test]
+    fn test_article_vote_counted() {
+        let (_redis, mut conn) = test_conn();
+
+        let article_id = post_article(&mut conn, "username", "A title", "https://www.google.com")
+            .expect("Article should be posted")
+            .to_string();
+        let mut article = "article:".to_owned();
+        article.push_str(&article_id);
+
+        println!("The first vote from a user should count, but the second shouldn't");
+        assert!(article_vote(&mut conn, "other_user", &article).unwrap());
+        assert!(!article_vote(&mut conn, "other_user", &article).unwrap());
+
+        let votes: usize = conn.hget(&article, "votes").unwrap();
+        println!("The article ended up with {votes} votes");
+        assert_eq!(votes, 2);
+
+        delete_article(&mut conn, &article_id);
+    }
+
+    #[test]
+    fn test_order_by_votes() {
+        let (_redis, mut conn) = test_conn();
+
+        let old_id = post_article(&mut conn, "username", "An old title", "http://google.com")
+            .expect("Article should be posted")
+            .to_string();
+        let mut old = "article:".to_owned();
+        old.push_str(&old_id);
+
+        println!("Let's pretend {old} was posted six days ago, then give it a few votes");
+        let six_days_ago = SystemTime::now() - Duration::from_secs(6 * 86400);
+        let six_days_ago = six_days_ago.duration_since(UNIX_EPOCH).unwrap().as_millis() as f64;
+        conn.zadd::<_, _, _, usize>("time:", &old, six_days_ago)
+            .unwrap();
+        conn.zadd::<_, _, _, usize>("score:", &old, six_days_ago + VOTE_SCORE)
+            .unwrap();
+        for user in ["user1", "user2", "user3"] {
+            article_vote(&mut conn, user, &old).unwrap();
+        }
+
+        let new_id = post_article(&mut conn, "username", "A new title", "http://google.com")
+            .expect("Article should be posted")
+            .to_string();
+        let mut new = "article:".to_owned();
+        new.push_str(&new_id);
+        article_vote(&mut conn, "user1", &new).unwrap();
+
+        let position = |articles: &[Article], id: &str| articles.iter().position(|a| a.id == id);
+        let (score_ranking, votes_ranking) = ("order-test:score:", "order-test:votes:");
+        rank_only(
+            &mut conn,
+            score_ranking,
+            ArticleOrder::Score.key(),
+            &[&old_id, &new_id],
+        );
+        rank_only(
+            &mut conn,
+            votes_ranking,
+            ArticleOrder::Votes.key(),
+            &[&old_id, &new_id],
+        );
+        let by_score = get_articles(&mut conn, 1, Some(score_ranking.to_owned())).unwrap();
+        let by_votes = get_articles(&mut conn, 1, Some(votes_ranking.to_owned())).unwrap();
+        println!(
+            "By score the new article is at {:?} and the old one at {:?}",
+            position(&by_score, &new_id),
+            position(&by_score, &old_id)
+        );
+        println!(
+            "By votes the old article is at {:?} and the new one at {:?}",
+            position(&by_votes, &old_id),
+            position(&by_votes, &new_id)
+        );
+        let old_score: f64 = conn.zscore("score:", &old).unwrap();
+        let new_score: f64 = conn.zscore("score:", &new).unwrap();
+        assert!(new_score > old_score);
+        assert!(position(&by_votes, &old_id).unwrap() < position(&by_votes, &new_id).unwrap());
+
+        let votes: usize = conn.zscore(ArticleOrder::Votes.key(), &old).unwrap();
+        assert_eq!(votes, 4);
+
+        conn.del::<_, usize>(&[score_ranking, votes_ranking])
+            .unwrap();
+        delete_article(&mut conn, &old_id);
+        delete_article(&mut conn, &new_id);
+    }
+
+    #[test]
+    fn test_article_time() {
+        let article = Article {
+            id: "1".to_owned(),
+            title: "A title".to_owned(),
+            link: "http://google.com".to_owned(),
+            poster: "username".to_owned(),
+            time: 1656676800123,
+            votes: 1,
+            score: 1656676800123.0 + VOTE_SCORE,
+            extra: HashMap::new(),
+        };
+        println!(
+            "An article posted at {} is {:?} old",
+            article.time,
+            article.age()
+        );
+        assert!(article.age() > Duration::from_secs(86400));
+
+        let future = Article {
+            time: article.time * 2,
+            ..article.clone()
+        };
+        assert_eq!(future.age(), Duration::ZERO);
+
+        #[cfg(feature = "rfc3339")]
+        {
+            println!("Formatted for display, that's {:?}", article.time_rfc3339());
+            assert_eq!(
+                article.time_rfc3339().as_deref(),
+                Some("2022-07-01T12:00:00.123Z")
+            );
+
+            let corrupt = Article {
+                time: u128::MAX,
+                ..article.clone()
+            };
+            assert_eq!(corrupt.time_rfc3339(), None);
+        }
+    }
+
+    #[test]
+    fn test_post_article_tagged() {
+        let (_redis, mut conn) = test_conn();
+
+        let tags = ["tagged-rust", "tagged-redis"];
+        let posted =
+            post_article_tagged(&mut conn, "username", "A title", "http://google.com", &tags)
+                .expect("Article should be posted");
+        println!("We posted article {} tagged with {tags:?}", posted.id);
+
+        for tag in tags {
+            let articles = get_group_articles_ex(&mut conn, tag, 1, None, true).unwrap();
+            println!("The {tag} group has {:?}", articles.articles);
+            assert!(articles.articles.contains(&posted));
+        }
+        let groups = list_groups(&mut conn).unwrap();
+        assert!(tags.iter().all(|tag| groups.contains(&tag.to_string())));
+
+        delete_article(&mut conn, &posted.id);
+        for tag in tags {
+            let mut group = "group:".to_owned();
+            group.push_str(tag);
+            conn.del::<_, usize>(&[group, group_cache_key("score:", tag)])
+                .unwrap();
+        }
+        conn.srem::<_, _, usize>("groups:", &tags).unwrap();
+    }
+
+    #[test]
+    fn test_get_group_page() {
+        let (_redis, mut conn) = test_conn();
+
+        println!("A group that was never created shouldn't exist");
+        let missing = get_group_page(&mut conn, "never-created-group", 1, None).unwrap();
+        println!("{missing:?}");
+        assert!(!missing.exists);
+        assert!(missing.page.items.is_empty());
+        assert_eq!(missing.page.total, 0);
+        assert!(!missing.page.has_next);
+
+        let posted = post_article_tagged(
+            &mut conn,
+            "username",
+            "A title",
+            "http://google.com",
+            &["paged"],
+        )
+        .unwrap();
+        let found = get_group_page(&mut conn, "paged", 1, None).unwrap();
+        println!("After posting an article to it, the paged group has {found:?}");
+        assert!(found.exists);
+        assert_eq!(found.page.items, vec![posted.clone()]);
+        assert_eq!(found.page.total, 1);
+        assert!(!found.page.has_next);
+
+        delete_article(&mut conn, &posted.id);
+        conn.del::<_, usize>(&["group:paged".to_owned(), group_cache_key("score:", "paged")])
+            .unwrap();
+        conn.srem::<_, _, usize>("groups:", "paged").unwrap();
+    }
+
+    #[test]
+    fn test_index_article() {
+        let (_redis, mut conn) = test_conn();
+
+        let posted =
+            post_article_full(&mut conn, "username", "A title", "http://google.com").unwrap();
+        let mut article = "article:".to_owned();
+        article.push_str(&posted.id);
+
+        println!("Replaying the indexing of {article} with a stale time and score changes nothing");
+        index_article(
+            &mut conn,
+            &article,
+            posted.time - 60_000,
+            posted.score - 1.0,
+        )
+        .unwrap();
+        let time: f64 = conn.zscore("time:", &article).unwrap();
+        let score: f64 = conn.zscore("score:", &article).unwrap();
+        assert_eq!(time, posted.time as f64);
+        assert_eq!(score, posted.score);
+
+        println!("But a newer score still goes through, while the time stays put");
+        index_article(
+            &mut conn,
+            &article,
+            posted.time + 60_000,
+            posted.score + VOTE_SCORE,
+        )
+        .unwrap();
+        let time: f64 = conn.zscore("time:", &article).unwrap();
+        let score: f64 = conn.zscore("score:", &article).unwrap();
+        assert_eq!(time, posted.time as f64);
+        assert_eq!(score, posted.score + VOTE_SCORE);
+
+        delete_article(&mut conn, &posted.id);
+    }
+
+    #[test]
+    fn test_article_ids() {
+        let (_redis, mut conn) = test_conn();
+
+        let ids = (0..3)
+            .map(|_| post_article(&mut conn, "username", "A title", "http://google.com").unwrap())
+            .collect::<Vec<ArticleId>>();
+        println!("We posted articles {ids:?}");
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let first = ids[0];
+        assert_eq!(first.to_string().parse::<ArticleId>().unwrap(), first);
+        assert!("not a number".parse::<ArticleId>().is_err());
+
+        for id in ids {
+            delete_article(&mut conn, &id.to_string());
+        }
+    }
+
+    #[test]
+    fn test_get_articles_skips_ghosts() {
+        let (_redis, mut conn) = test_conn();
+
+        let posted =
+            post_article_full(&mut conn, "username", "A title", "http://google.com").unwrap();
+        let mut article = "article:".to_owned();
+        article.push_str(&posted.id);
+
+        let ranking = "ghost-test:";
+        rank_only(&mut conn, ranking, "score:", &[&posted.id]);
+
+        println!("Let's delete {article}'s HASH, but leave it ranked as if a delete crashed");
+        conn.del::<_, usize>(&article).unwrap();
+
+        let articles = get_articles(&mut conn, 1, Some(ranking.to_owned()))
+            .expect("Ghosts shouldn't be an error");
+        assert!(articles.is_empty());
+        let left: usize = conn.zcard(ranking).unwrap();
+        assert_eq!(left, 0);
+        for order in ["score:", "time:", "votes:"] {
+            let rank: Option<usize> = conn.zrank(order, &article).unwrap();
+            println!("{article} is now ranked {rank:?} in {order}");
+            assert!(rank.is_none());
+        }
+
+        delete_article(&mut conn, &posted.id);
+    }
+
+    #[test]
+    fn test_export_articles() {
+        let (_redis, mut conn) = test_conn();
+
+        let ids = ["First", "Second, with a comma", "Third \"quoted\""]
+            .map(|title| post_article(&mut conn, "username", title, "http://google.com").unwrap())
+            .map(|id| id.to_string());
+
+        let mut exported = vec![];
+        let count = export_articles(&mut conn, &mut exported, ExportFormat::JsonLines)
+            .expect("Exporting shouldn't err");
+        let exported = String::from_utf8(exported).unwrap();
+        println!("Exported {count} articles, ours being:");
+        let ours = exported
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|article| ids.iter().any(|id| article["id"] == id.as_str()))
+            .collect::<Vec<_>>();
+        for article in &ours {
+            println!("{article}");
+        }
+        assert_eq!(ours.len(), 3);
+        assert!(count >= 3);
+        assert_eq!(exported.lines().count(), count);
+
+        let mut exported = vec![];
+        export_articles(&mut conn, &mut exported, ExportFormat::Csv).unwrap();
+        let exported = String::from_utf8(exported).unwrap();
+        assert_eq!(
+            exported.lines().next(),
+            Some("id,link,poster,score,time,title,votes")
+        );
+        let quoted = exported
+            .lines()
+            .find(|line| line.starts_with(&format!("{},", ids[2])))
+            .expect("The quoted article should be exported");
+        println!("As CSV, the quoted article is: {quoted}");
+        assert!(quoted.contains(",\"Third \"\"quoted\"\"\","));
+
+        for id in ids {
+            delete_article(&mut conn, &id);
+        }
+    }
+
+    #[test]
+    fn test_import_articles() {
+        let (_redis, mut conn) = test_conn();
+
+        let originals = ["First", "Second, with a comma", "Third\nover two lines"].map(|title| {
+            post_article_full(&mut conn, "username", title, "http://google.com").unwrap()
+        });
+        let is_ours = |id: &str| originals.iter().any(|article| article.id == id);
+
+        for format in [ExportFormat::JsonLines, ExportFormat::Csv] {
+            println!("Let's back up our articles as {format:?}, then delete them");
+            let mut exported = vec![];
+            export_articles(&mut conn, &mut exported, format).unwrap();
+            for article in &originals {
+                delete_article(&mut conn, &article.id);
+            }
+
+            // Only restore our own articles, since the rest of the export is still around
+            let exported = String::from_utf8(exported).unwrap();
+            let backup = match format {
+                ExportFormat::JsonLines => exported
+                    .lines()
+                    .filter(|line| {
+                        let article: serde_json::Value = serde_json::from_str(line).unwrap();
+                        is_ours(article["id"].as_str().unwrap())
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                ExportFormat::Csv => {
+                    let mut lines = exported
+                        .lines()
+                        .map(|line| Ok::<_, std::io::Error>(line.to_owned()));
+                    let mut backup = vec![];
+                    while let Some(row) = read_csv_record(&mut lines).unwrap() {
+                        if backup.is_empty() || is_ours(&row[0]) {
+                            let row = row.iter().map(|field| csv_field(field)).collect::<Vec<_>>();
+                            backup.push(row.join(","));
+                        }
+                    }
+                    backup.join("\n")
+                }
+            };
+
+            let imported = import_articles(&mut conn, backup.as_bytes(), format, false)
+                .expect("Importing shouldn't err");
+            println!("We imported {imported} articles");
+            assert_eq!(imported, 3);
+            let ranking = "import-test:";
+            let ids = originals
+                .iter()
+                .map(|article| article.id.as_str())
+                .collect::<Vec<_>>();
+            rank_only(&mut conn, ranking, "score:", &ids);
+            let restored = get_articles(&mut conn, 1, Some(ranking.to_owned())).unwrap();
+            conn.del::<_, usize>(ranking).unwrap();
+            for article in &originals {
+                assert!(restored.contains(article));
+            }
+
+            println!("Importing again without overwriting should skip all of them");
+            let imported = import_articles(&mut conn, backup.as_bytes(), format, false).unwrap();
+            assert_eq!(imported, 0);
+        }
+
+        let counter: u64 = conn.get("article:").unwrap();
+        let max_id: u64 = originals
+            .iter()
+            .map(|a| a.id.parse::<u64>().unwrap())
+            .max()
+            .unwrap();
+        assert!(counter >= max_id);
+
+        for article in &originals {
+            delete_article(&mut conn, &article.id);
+        }
+    }
+
+    test_support::fake_connection!();
+
+    // Stands in for a Redis whose clock reads `now`, to check which clock a function consults. Every
+    // article was posted right now according to the local clock.
+    fn fake_clock(now: Duration) -> FakeConnection<impl FnMut(&[String]) -> Value> {
+        FakeConnection::new(move |command| match command[0].as_str() {
+            "TIME" => Value::Bulk(vec![
+                Value::Data(now.as_secs().to_string().into_bytes()),
+                Value::Data(now.subsec_micros().to_string().into_bytes()),
+            ]),
+            "ZSCORE" => {
+                let posted = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                Value::Data(posted.as_millis().to_string().into_bytes())
+            }
+            _ => Value::Okay,
+        })
+    }
+
+    #[test]
+    fn test_clock_source() {
+        let local = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let now = local + Duration::from_secs(8 * 86400);
+        let mut fake = fake_clock(now);
+        println!("Redis's clock is 8 days ahead, so a brand new article is too old to vote on");
+        let error =
+            article_vote_with_clock(&mut fake, "username", "article:0", 1, ClockSource::Redis)
+                .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<AppError>(),
+            Some(&AppError::TooOld("article:0".to_owned()))
+        );
+        assert_eq!(fake.commands, ["TIME", "ZSCORE"]);
+        assert_eq!(ClockSource::Redis.now(&mut fake).unwrap(), now);
+        assert!(ClockSource::Local.now(&mut fake).unwrap() < now);
+        assert_eq!(fake.commands.len(), 3);
+
+        let (_redis, mut conn) = test_conn();
+        let article = post_article_with_clock(
+            &mut conn,
+            "username",
+            "A title",
+            "http://google.com",
+            &[],
+            ClockSource::Redis,
+        )
+        .unwrap();
+        println!("Posting by Redis's clock gives {article:?}");
+        let local = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        assert!(local.abs_diff(article.time) < 60_000);
+
+        let mut id = "article:".to_owned();
+        id.push_str(&article.id);
+        let counted =
+            article_vote_with_clock(&mut conn, "other_user", &id, 1, ClockSource::Redis).unwrap();
+        assert!(counted);
+
+        delete_article(&mut conn, &article.id);
+    }
+
+    #[test]
+    fn test_get_articles_after() {
+        let (_redis, mut conn) = test_conn();
+
+        // Our articles are dated a day from now (a second apart) so that they're at the top of
+        // `time:` in a known order, no matter what else has been posted
+        let tomorrow = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+            + 86_400_000;
+        let mut ids = vec![];
+        for i in 0..50 {
+            let id = post_article(&mut conn, "username", "A title", "http://google.com")
+                .unwrap()
+                .to_string();
+            let mut article = "article:".to_owned();
+            article.push_str(&id);
+            conn.zadd::<_, _, _, usize>("time:", &article, (tomorrow + i * 1000) as f64)
+                .unwrap();
+            ids.push(id);
         }
-        println!();
-        assert!(articles.len() >= 1);
 
-        add_remove_groups::<_, _, &str>(&mut conn, article_id, vec!["new-group"], vec![]).unwrap();
-        println!("We added the article to a new group, other articles include:");
-        let articles = get_group_articles(&mut conn, "new-group", 1, None).unwrap();
-        for article in &articles {
-            println!("{article:?}");
+        println!("Let's walk the newest 50 articles 10 at a time");
+        let mut walked = vec![];
+        let mut cursor = None;
+        for _ in 0..5 {
+            let (articles, next) =
+                get_articles_after(&mut conn, ArticleOrder::Time, cursor, 10).unwrap();
+            assert_eq!(articles.len(), 10);
+            assert!(next.is_some());
+            walked.extend(articles.into_iter().map(|article| article.id));
+            cursor = next;
         }
-        println!();
-        assert!(articles.len() >= 1);
+        println!("We walked {walked:?}");
+        let newest_first = ids.iter().rev().cloned().collect::<Vec<_>>();
+        assert_eq!(walked, newest_first);
 
-        let keys = ["article:*", "group:*", "score:*", "time:*", "voted:*"];
-        for key in keys {
-            let sub_keys: Vec<String> = conn.keys(key).unwrap();
-            for sub_key in sub_keys {
-                conn.del::<_, usize>(sub_key).unwrap();
+        let (articles, _) = get_articles_after(&mut conn, ArticleOrder::Time, cursor, 10).unwrap();
+        assert!(articles.iter().all(|article| !ids.contains(&article.id)));
+        let (articles, next) =
+            get_articles_after(&mut conn, ArticleOrder::Time, Some(0.0), 10).unwrap();
+        assert!(articles.is_empty());
+        assert_eq!(next, None);
+
+        for id in &ids {
+            delete_article(&mut conn, id);
+        }
+    }
+
+    #[test]
+    fn test_group_order_validation() {
+        let (_redis, mut conn) = test_conn();
+
+        assert_eq!("votes:".parse::<ArticleOrder>(), Ok(ArticleOrder::Votes));
+        let typo = get_group_articles(&mut conn, "programming", 1, Some("scores:".to_owned()))
+            .unwrap_err()
+            .to_string();
+        println!("Ordering by a typo gives: {typo}");
+        assert!(typo.contains("'scores:' isn't an order"));
+        let typo = get_group_page(&mut conn, "never-created-group", 1, Some("time".to_owned()))
+            .unwrap_err();
+        assert!(typo.to_string().contains("'time' isn't an order"));
+
+        let cached: Vec<String> = conn.keys("groupcache:scores:*").unwrap();
+        assert!(cached.is_empty());
+    }
+
+    #[test]
+    fn test_with_retry() {
+        println!("An op that drops the connection twice should succeed on the third attempt");
+        let mut calls = 0;
+        let result = with_retry(3, Duration::from_millis(10), || {
+            calls += 1;
+            if calls <= 2 {
+                Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset).into())
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        println!("...but not if it only gets two attempts");
+        let mut calls = 0;
+        let result: RedisResult<()> = with_retry(2, Duration::from_millis(10), || {
+            calls += 1;
+            Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset).into())
+        });
+        assert!(result.unwrap_err().is_io_error());
+        assert_eq!(calls, 2);
+
+        println!("Logical errors shouldn't be retried at all");
+        let mut calls = 0;
+        let result: RedisResult<()> = with_retry(3, Duration::from_millis(10), || {
+            calls += 1;
+            Err((redis::ErrorKind::TypeError, "WRONGTYPE").into())
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_post_article_bad_link() {
+        let (_redis, mut conn) = test_conn();
+
+        // Nothing should even reach Redis
+        let mut fake = fake_clock(Duration::ZERO);
+        for link in ["not a url", "ftp://example.com/file", "http://"] {
+            let error = post_article(&mut fake, "username", "A title", link).unwrap_err();
+            println!("Posting {link} gives: {error}");
+            assert!(matches!(
+                error.downcast_ref::<AppError>(),
+                Some(AppError::BadInput(_))
+            ));
+        }
+        assert!(fake.commands.is_empty());
+
+        println!("Other schemes can be allowed though");
+        let options = PostOptions {
+            link_schemes: vec!["ftp".to_owned()],
+            ..PostOptions::default()
+        };
+        let article = post_article_with(
+            &mut conn,
+            "username",
+            "A title",
+            "ftp://example.com/file",
+            &options,
+        )
+        .unwrap();
+        assert_eq!(article.link, "ftp://example.com/file");
+        delete_article(&mut conn, &article.id);
+    }
+
+    #[test]
+    fn test_get_blended_articles() {
+        let (_redis, mut conn) = test_conn();
+
+        println!("Let's post a popular article, then a newer one with fewer votes");
+        let popular = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+        let newer = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+        let mut popular_key = "article:".to_owned();
+        popular_key.push_str(&popular);
+        let mut newer_key = "article:".to_owned();
+        newer_key.push_str(&newer);
+        for user in ["other_user", "another_user"] {
+            article_vote(&mut conn, user, &popular_key).unwrap();
+        }
+        // Both are dated a day from now, 10 seconds apart, to keep them at the top of every blend
+        let tomorrow = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as f64
+            + 86_400_000.0;
+        conn.zadd::<_, _, _, usize>("time:", &popular_key, tomorrow)
+            .unwrap();
+        conn.zadd::<_, _, _, usize>("time:", &newer_key, tomorrow + 10_000.0)
+            .unwrap();
+
+        let ranked = |conn: &mut Connection, time_weight: f64| {
+            get_blended_articles(conn, 100_000.0, time_weight, 1)
+                .unwrap()
+                .into_iter()
+                .map(|article| article.id)
+                .take(2)
+                .collect::<Vec<_>>()
+        };
+        println!("When votes count for more, the popular article comes first");
+        assert_eq!(ranked(&mut conn, 1.0), [popular.clone(), newer.clone()]);
+        println!("But when recency counts for more, the newer one does");
+        assert_eq!(ranked(&mut conn, 100.0), [newer.clone(), popular.clone()]);
+
+        let leftover: bool = conn.exists("blended:").unwrap();
+        assert!(!leftover);
+        delete_article(&mut conn, &popular);
+        delete_article(&mut conn, &newer);
+    }
+
+    #[test]
+    fn test_vote_on_missing_article() {
+        let (_redis, mut conn) = test_conn();
+
+        let error = article_vote(&mut conn, "username", "article:999999").unwrap_err();
+        println!("Voting on an article that was never posted gives: {error}");
+        assert_eq!(
+            error.downcast_ref::<AppError>(),
+            Some(&AppError::NotFound("article:999999".to_owned()))
+        );
+        let voted: bool = conn.exists("voted:999999").unwrap();
+        assert!(!voted);
+    }
+
+    #[test]
+    fn test_concurrent_votes_notify_once() {
+        let (_redis, client) = test_client();
+        let mut conn = client
+            .get_connection()
+            .expect("Should be able to Establish Connection");
+        let mut subscriber = client
+            .get_connection()
+            .expect("Should be able to Establish Connection");
+        let mut pubsub = subscriber.as_pubsub();
+        pubsub.subscribe(VOTE_CHANNEL).unwrap();
+        pubsub
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+
+        let article_id = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+        let mut article = "article:".to_owned();
+        article.push_str(&article_id);
+
+        println!("Two app instances record the same vote at the same time");
+        let handles = (0..2)
+            .map(|_| {
+                let client = client.clone();
+                let article = article.clone();
+                thread::spawn(move || {
+                    let mut conn = client.get_connection().unwrap();
+                    article_vote(&mut conn, "other_user", &article).unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        let counted = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>();
+        println!("Which of them counted it? {counted:?}");
+        assert_eq!(counted.iter().filter(|counted| **counted).count(), 1);
+
+        let mut notifications = vec![];
+        while let Ok(message) = pubsub.get_message() {
+            let payload: serde_json::Value =
+                serde_json::from_str(&message.get_payload::<String>().unwrap()).unwrap();
+            if payload["article"] == article.as_str() {
+                notifications.push(payload);
+            }
+        }
+        println!("And it was announced as: {notifications:?}");
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0]["user"], "other_user");
+        let votes: usize = conn.hget(&article, "votes").unwrap();
+        assert_eq!(votes, 2);
+
+        delete_article(&mut conn, &article_id);
+    }
+
+    #[test]
+    fn test_post_article_id_collision() {
+        let (_redis, mut conn) = test_conn();
+
+        // Far enough ahead of the counter that no other post will be handed this id by accident
+        let counter: u64 = conn.get("article:").unwrap_or(0);
+        let taken = (counter + 1000).to_string();
+        let mut existing = "article:".to_owned();
+        existing.push_str(&taken);
+        conn.hset::<_, _, _, usize>(&existing, "title", "Already here")
+            .unwrap();
+
+        println!("Someone set the article: counter back to just before {existing}");
+        conn.set::<_, _, ()>("article:", counter + 999).unwrap();
+        let error =
+            post_article(&mut conn, "username", "A title", "http://google.com").unwrap_err();
+        println!("Posting now gives: {error}");
+        assert_eq!(
+            error.downcast_ref::<AppError>(),
+            Some(&AppError::Conflict(existing.clone()))
+        );
+        let title: String = conn.hget(&existing, "title").unwrap();
+        assert_eq!(title, "Already here");
+        let mut voted = "voted:".to_owned();
+        voted.push_str(&taken);
+        let voted: bool = conn.exists(voted).unwrap();
+        assert!(!voted);
+
+        println!("The id was used up though, so the next post gets a fresh one");
+        let next = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+        assert_ne!(next, taken);
+
+        delete_article(&mut conn, &taken);
+        delete_article(&mut conn, &next);
+    }
+
+    #[test]
+    fn test_str_and_string_arguments() {
+        let (_redis, mut conn) = test_conn();
+
+        let article_id = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+        let mut article = "article:".to_owned();
+        article.push_str(&article_id);
+
+        println!("Voting takes borrowed and owned strings alike");
+        let start = Instant::now();
+        for voter in 0..100 {
+            let voter = voter.to_string();
+            assert!(article_vote(&mut conn, voter.as_str(), article.as_str()).unwrap());
+        }
+        println!("100 votes with &str arguments took {:?}", start.elapsed());
+        assert!(article_vote(&mut conn, "owned".to_owned(), article.clone()).unwrap());
+        assert!(article_unvote(&mut conn, "owned".to_owned(), article.clone()).unwrap());
+        assert!(article_unvote(&mut conn, "0", &article).unwrap());
+        let votes: usize = conn.hget(&article, "votes").unwrap();
+        assert_eq!(votes, 100);
+
+        println!("And so do the group functions");
+        let group = "str-string-group".to_owned();
+        add_remove_groups::<_, _, &str>(
+            &mut conn,
+            article_id.as_str(),
+            vec![group.as_str()],
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(group_size(&mut conn, group.as_str()).unwrap(), 1);
+        assert_eq!(group_size(&mut conn, group.clone()).unwrap(), 1);
+        let page = get_group_page(&mut conn, group.clone(), 1, None).unwrap();
+        assert_eq!(page.page.total, 1);
+        let articles = get_group_articles(&mut conn, "str-string-group", 1, None).unwrap();
+        assert_eq!(articles.len(), 1);
+        add_remove_groups::<_, &str, _>(&mut conn, article_id.clone(), vec![], vec![group])
+            .unwrap();
+
+        delete_article(&mut conn, &article_id);
+    }
+
+    #[test]
+    fn test_get_articles_by_ids() {
+        let (_redis, mut conn) = test_conn();
+
+        let ids = ["First", "Second", "Third"]
+            .map(|title| post_article(&mut conn, "username", title, "http://google.com").unwrap());
+        let missing = ArticleId(u64::MAX);
+
+        println!("Let's fetch them back out of order, along with one that doesn't exist");
+        let wanted = [ids[2], missing, ids[0], ids[1]];
+        let articles = get_articles_by_ids(&mut conn, &wanted).unwrap();
+        let titles = articles
+            .iter()
+            .map(|article| article.as_ref().map(|article| article.title.as_str()))
+            .collect::<Vec<_>>();
+        println!("We got: {titles:?}");
+        assert_eq!(titles, [Some("Third"), None, Some("First"), Some("Second")]);
+        assert_eq!(articles[0].as_ref().unwrap().id, ids[2].to_string());
+        assert_eq!(articles[0].as_ref().unwrap().votes, 1);
+        assert!(get_articles_by_ids(&mut conn, &[]).unwrap().is_empty());
+
+        for id in ids {
+            delete_article(&mut conn, &id.to_string());
+        }
+    }
+
+    #[test]
+    fn test_group_cache_invalidation() {
+        let (_redis, mut conn) = test_conn();
+
+        let first = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+        add_remove_groups::<_, _, &str>(&mut conn, &first, vec!["invalidate-group"], vec![])
+            .unwrap();
+        let result = get_group_articles_ex(&mut conn, "invalidate-group", 1, None, false).unwrap();
+        assert_eq!(result.articles.len(), 1);
+
+        println!("Adding a second article to the group...");
+        let second = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+        add_remove_groups::<_, _, &str>(&mut conn, &second, vec!["invalidate-group"], vec![])
+            .unwrap();
+        let result = get_group_articles_ex(&mut conn, "invalidate-group", 1, None, false).unwrap();
+        println!(
+            "Shows up right away: {} article(s), cached: {}",
+            result.articles.len(),
+            result.cached
+        );
+        assert!(!result.cached);
+        assert_eq!(result.articles.len(), 2);
+
+        println!("As does taking one out of it, in any order");
+        let by_time =
+            get_group_articles(&mut conn, "invalidate-group", 1, Some("time:".to_owned())).unwrap();
+        assert_eq!(by_time.len(), 2);
+        add_remove_groups::<_, &str, _>(&mut conn, &first, vec![], vec!["invalidate-group"])
+            .unwrap();
+        let by_time =
+            get_group_articles(&mut conn, "invalidate-group", 1, Some("time:".to_owned())).unwrap();
+        assert_eq!(by_time.len(), 1);
+        let by_score = get_group_articles(&mut conn, "invalidate-group", 1, None).unwrap();
+        assert_eq!(by_score.len(), 1);
+
+        println!("Or posting an article that's tagged with it");
+        let third = post_article_tagged(
+            &mut conn,
+            "username",
+            "A title",
+            "http://google.com",
+            &["invalidate-group"],
+        )
+        .unwrap()
+        .id;
+        let by_score = get_group_articles(&mut conn, "invalidate-group", 1, None).unwrap();
+        assert_eq!(by_score.len(), 2);
+
+        delete_article(&mut conn, &first);
+        delete_article(&mut conn, &second);
+        delete_article(&mut conn, &third);
+        conn.del::<_, usize>(&[
+            "group:invalidate-group",
+            "groupcache:score::invalidate-group",
+            "groupcache:time::invalidate-group",
+            "groupcache_index:invalidate-group",
+        ])
+        .unwrap();
+        conn.srem::<_, _, usize>("groups:", "invalidate-group")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_article_ordering() {
+        let article = |id: &str, votes: usize, time: u128| Article {
+            id: id.to_owned(),
+            title: "A title".to_owned(),
+            link: "http://google.com".to_owned(),
+            poster: "username".to_owned(),
+            time,
+            votes,
+            score: time as f64 + votes as f64 * VOTE_SCORE,
+            extra: HashMap::new(),
+        };
+        let popular = article("1", 10, 1_000);
+        let newer = article("2", 2, 5_000);
+        let older = article("3", 2, 4_000);
+        let fresh = article("4", 1, 900_000);
+
+        let mut articles = vec![older.clone(), fresh.clone(), popular.clone(), newer.clone()];
+        articles.sort();
+        let ids = articles.iter().map(|a| a.id.as_str()).collect::<Vec<_>>();
+        println!("By votes, then time: {ids:?}");
+        assert_eq!(ids, ["1", "2", "3", "4"]);
+
+        sort_by_score(&mut articles);
+        let ids = articles.iter().map(|a| a.id.as_str()).collect::<Vec<_>>();
+        println!("By score: {ids:?}");
+        assert_eq!(ids, ["4", "2", "1", "3"]);
+
+        let mut twin = popular.clone();
+        assert_eq!(popular.cmp(&twin), Ordering::Equal);
+        twin.title = "Another title".to_owned();
+        assert_ne!(popular.cmp(&twin), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_vote_with_scheme() {
+        let (_redis, mut conn) = test_conn();
+
+        let ns = Namespace::new("scheme-test");
+        let article = ns.key("article:1");
+        assert_eq!(DefaultScheme.article_id("article:12"), Some("12"));
+        assert_eq!(ns.article_id(&article), Some("1"));
+        assert_eq!(ns.article_id("article:1"), None);
+
+        println!(
+            "Let's vote on {article}, which lives under the {} namespace",
+            ns.0
+        );
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        conn.zadd::<_, _, _, usize>(ns.time(), &article, now.as_millis() as f64)
+            .unwrap();
+        conn.zadd::<_, _, _, usize>(ns.score(), &article, 0)
+            .unwrap();
+        conn.hset::<_, _, _, usize>(&article, "votes", 0).unwrap();
+        let counted = article_vote_with_scheme(
+            &mut conn,
+            &ns,
+            "scheme_voter",
+            &article,
+            1,
+            ClockSource::Local,
+        )
+        .unwrap();
+        assert!(counted);
+        #[cfg(feature = "vote-log")]
+        {
+            let events = read_vote_events(&mut conn, &ns, 10).unwrap();
+            println!("The vote was logged under the namespace too: {events:?}");
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].article, article);
+        }
+
+        let in_ns: bool = conn.sismember(ns.voted("1"), "scheme_voter").unwrap();
+        let unprefixed: bool = conn.sismember("voted:1", "scheme_voter").unwrap();
+        let score: f64 = conn.zscore(ns.score(), &article).unwrap();
+        println!(
+            "Recorded in {}: {in_ns}, in voted:1: {unprefixed}",
+            ns.voted("1")
+        );
+        assert!(in_ns);
+        assert!(!unprefixed);
+        assert_eq!(score, VOTE_SCORE);
+
+        assert!(article_unvote_with_scheme(&mut conn, &ns, "scheme_voter", &article).unwrap());
+        let in_ns: bool = conn.sismember(ns.voted("1"), "scheme_voter").unwrap();
+        assert!(!in_ns);
+
+        conn.del::<_, usize>(&[
+            article.clone(),
+            ns.voted("1"),
+            ns.vote_weights("1"),
+            ns.time(),
+            ns.score(),
+            ns.votes(),
+            ns.key("votes:stream"),
+        ])
+        .unwrap();
+    }
+
+    #[test]
+    fn test_get_articles_with_groups() {
+        let (_redis, mut conn) = test_conn();
+
+        let first = post_article_tagged(
+            &mut conn,
+            "username",
+            "A title",
+            "http://google.com",
+            &["chips-a"],
+        )
+        .unwrap();
+        let second = post_article_tagged(
+            &mut conn,
+            "username",
+            "Another title",
+            "http://google.com",
+            &["chips-b"],
+        )
+        .unwrap();
+        add_remove_groups::<_, _, &str>(&mut conn, &second.id, vec!["chips-c"], vec![]).unwrap();
+
+        println!("Let's read our articles, newest first, along with their groups");
+        let ranking = "with-groups-test:";
+        rank_only(&mut conn, ranking, "time:", &[&first.id, &second.id]);
+        let page = get_articles_with_groups(&mut conn, 1, Some(ranking.to_owned())).unwrap();
+        conn.del::<_, usize>(ranking).unwrap();
+        let groups_of = |id: &str| {
+            page.iter()
+                .find(|(article, _)| article.id == id)
+                .map(|(_, groups)| groups.clone())
+                .expect("Both articles should be on the first page")
+        };
+        println!("{}: {:?}", first.id, groups_of(&first.id));
+        println!("{}: {:?}", second.id, groups_of(&second.id));
+        assert_eq!(groups_of(&first.id), ["chips-a"]);
+        assert_eq!(groups_of(&second.id), ["chips-b", "chips-c"]);
+
+        delete_article(&mut conn, &first.id);
+        delete_article(&mut conn, &second.id);
+        conn.del::<_, usize>(&["group:chips-a", "group:chips-b", "group:chips-c"])
+            .unwrap();
+        conn.srem::<_, _, usize>("groups:", &["chips-a", "chips-b", "chips-c"])
+            .unwrap();
+    }
+
+    #[test]
+    fn test_client_error() {
+        let cases = [
+            (AppError::BadInput("no title".to_owned()), "bad_input"),
+            (AppError::NotFound("article:1".to_owned()), "not_found"),
+            (AppError::Conflict("article:1".to_owned()), "conflict"),
+            (AppError::TooOld("article:1".to_owned()), "too_old"),
+            (AppError::RateLimited("username".to_owned()), "rate_limited"),
+        ];
+        for (e, code) in cases {
+            let message = e.to_string();
+            let client_error = ClientError::from(e);
+            println!("{}", serde_json::to_string(&client_error).unwrap());
+            assert_eq!(client_error.code, code);
+            assert_eq!(client_error.message, message);
+        }
+
+        println!("Anything else is hidden behind a generic internal error");
+        let redis_error: Box<dyn Error> =
+            RedisError::from((ErrorKind::IoError, "connection refused by 10.0.0.5")).into();
+        let serde_error: Box<dyn Error> = serde_json::from_str::<Article>("{").unwrap_err().into();
+        for e in [redis_error, serde_error] {
+            let client_error = ClientError::from(e);
+            assert_eq!(
+                serde_json::to_value(&client_error).unwrap(),
+                serde_json::json!({"code": "internal", "message": "internal error"})
+            );
+        }
+
+        let wrapped: Box<dyn Error> = AppError::NotFound("article:2".to_owned()).into();
+        assert_eq!(ClientError::from(wrapped).code, "not_found");
+    }
+
+    #[test]
+    fn test_post_article_title_validation() {
+        let (_redis, mut conn) = test_conn();
+
+        let too_long = "a".repeat(513);
+        for title in ["", "   \t\n ", too_long.as_str()] {
+            let error =
+                post_article(&mut conn, "username", title, "http://google.com").unwrap_err();
+            println!(
+                "Posting with a {} character title gives: {error}",
+                title.len()
+            );
+            assert!(matches!(
+                error.downcast_ref::<AppError>(),
+                Some(AppError::BadInput(_))
+            ));
+        }
+
+        println!("Titles are trimmed before they're checked and stored");
+        let posted =
+            post_article_full(&mut conn, "username", "  A title  ", "http://google.com").unwrap();
+        assert_eq!(posted.title, "A title");
+        let stored = get_article(&mut conn, &posted.id).unwrap().unwrap();
+        assert_eq!(stored.title, "A title");
+
+        let strict = PostOptions {
+            min_title_length: 10,
+            max_title_length: 20,
+            ..PostOptions::default()
+        };
+        assert!(post_article_with(
+            &mut conn,
+            "username",
+            "Too short",
+            "http://google.com",
+            &strict
+        )
+        .is_err());
+
+        delete_article(&mut conn, &posted.id);
+    }
+
+    // Passes everything through to a real connection, but keeps a log of every command sent (as its
+    // name and arguments) and how many round-trips they took, so tests can check what a function
+    // actually asks Redis for without MONITOR.
+    struct RecordingConnection<C> {
+        inner: C,
+        commands: Vec<String>,
+        round_trips: usize,
+    }
+
+    impl<C: ConnectionLike> RecordingConnection<C> {
+        fn new(inner: C) -> RecordingConnection<C> {
+            RecordingConnection {
+                inner,
+                commands: vec![],
+                round_trips: 0,
+            }
+        }
+
+        fn commands(&self) -> &[String] {
+            &self.commands
+        }
+
+        fn record(&mut self, packed: &[u8]) {
+            self.round_trips += 1;
+            let commands = unpack_commands(packed)
+                .into_iter()
+                .map(|args| args.join(" "));
+            self.commands.extend(commands);
+        }
+    }
+
+    impl<C: ConnectionLike> ConnectionLike for RecordingConnection<C> {
+        fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+            self.record(cmd);
+            self.inner.req_packed_command(cmd)
+        }
+
+        fn req_packed_commands(
+            &mut self,
+            cmd: &[u8],
+            offset: usize,
+            count: usize,
+        ) -> RedisResult<Vec<Value>> {
+            self.record(cmd);
+            self.inner.req_packed_commands(cmd, offset, count)
+        }
+
+        fn get_db(&self) -> i64 {
+            self.inner.get_db()
+        }
+
+        fn check_connection(&mut self) -> bool {
+            self.inner.check_connection()
+        }
+
+        fn is_open(&self) -> bool {
+            self.inner.is_open()
+        }
+    }
+
+    #[test]
+    fn test_recording_connection() {
+        let (_redis, conn) = test_conn();
+        let mut conn = RecordingConnection::new(conn);
+
+        let article =
+            post_article_full(&mut conn, "username", "A title", "http://google.com").unwrap();
+        println!("Posting an article took {} round-trips", conn.round_trips);
+        assert!(conn
+            .commands()
+            .iter()
+            .any(|command| command == "INCRBY article: 1"));
+
+        // A ranking of the test's own, so that nothing else on the server is on the page
+        let ranking = "recording-test:score:";
+        rank_only(&mut conn.inner, ranking, "score:", &[&article.id]);
+        let mut conn = RecordingConnection::new(conn.inner);
+        let articles = get_articles(&mut conn, 1, Some(ranking.to_owned())).unwrap();
+        println!(
+            "Getting {} articles took {} round-trips for {} commands: {:?}",
+            articles.len(),
+            conn.round_trips,
+            conn.commands().len(),
+            conn.commands()
+        );
+        assert_eq!(articles.len(), 1);
+        assert!(conn.commands()[0].starts_with("ZREVRANGE recording-test:score: 0 24"));
+        // One ZREVRANGE, then every article's SISMEMBER, HGETALL and ZSCORE in a single pipeline
+        assert_eq!(conn.round_trips, 2);
+        let mut key = "article:".to_owned();
+        key.push_str(&article.id);
+        for command in ["SISMEMBER hidden:", "HGETALL", "ZSCORE score:"] {
+            assert!(conn
+                .commands()
+                .iter()
+                .any(|recorded| recorded.starts_with(command) && recorded.ends_with(&key)));
+        }
+
+        conn.inner.del::<_, usize>(ranking).unwrap();
+        delete_article(&mut conn.inner, &article.id);
+    }
+
+    #[test]
+    fn test_top_per_group() {
+        let (_redis, mut conn) = test_conn();
+
+        let groups = ["top-a", "top-b", "top-c"];
+        let mut posted = vec![];
+        for (i, group) in groups.iter().enumerate() {
+            for _ in 0..=i {
+                let article = post_article_tagged(
+                    &mut conn,
+                    "username",
+                    "A title",
+                    "http://google.com",
+                    &[group],
+                )
+                .unwrap();
+                posted.push((group.to_string(), article));
+                // So that no two articles are posted in the same millisecond, and tie on score
+                thread::sleep(Duration::from_millis(2));
+            }
+        }
+        // Votes put the second article of top-c ahead of the first
+        let (_, second) = &posted[4];
+        let mut second_key = "article:".to_owned();
+        second_key.push_str(&second.id);
+        article_vote(&mut conn, "other_user", &second_key).unwrap();
+
+        println!("Let's get the top 2 articles of {groups:?} at once");
+        let top = top_per_group(&mut conn, &groups, 2, None).unwrap();
+        for group in groups {
+            let ids = top[group]
+                .iter()
+                .map(|article| article.id.as_str())
+                .collect::<Vec<_>>();
+            println!("{group}: {ids:?}");
+        }
+        let ids_of = |group: &str| {
+            top[group]
+                .iter()
+                .map(|article| article.id.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids_of("top-a"), [posted[0].1.id.clone()]);
+        assert_eq!(
+            ids_of("top-b"),
+            [posted[2].1.id.clone(), posted[1].1.id.clone()]
+        );
+        assert_eq!(
+            ids_of("top-c"),
+            [posted[4].1.id.clone(), posted[5].1.id.clone()]
+        );
+
+        for (_, article) in &posted {
+            delete_article(&mut conn, &article.id);
+        }
+        for group in groups {
+            let mut group_key = "group:".to_owned();
+            group_key.push_str(group);
+            conn.del::<_, usize>(&group_key).unwrap();
+            conn.srem::<_, _, usize>("groups:", group).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_downvote_hides_article() {
+        let (_redis, mut conn) = test_conn();
+
+        let posted =
+            post_article_full(&mut conn, "username", "A title", "http://google.com").unwrap();
+        let mut article = "article:".to_owned();
+        article.push_str(&posted.id);
+        let in_feed = |conn: &mut Connection| {
+            get_articles(conn, 1, Some("time:".to_owned()))
+                .unwrap()
+                .iter()
+                .any(|listed| listed.id == posted.id)
+        };
+        assert!(in_feed(&mut conn));
+
+        println!("The poster already voted for it, so they can't vote against it");
+        assert!(!article_downvote(&mut conn, "username", &article, Some(3)).unwrap());
+
+        println!("Let's downvote {article} up to a threshold of 3");
+        for user in ["user1", "user2"] {
+            assert!(article_downvote(&mut conn, user, &article, Some(3)).unwrap());
+        }
+        assert!(!article_downvote(&mut conn, "user2", &article, Some(3)).unwrap());
+        assert!(in_feed(&mut conn));
+        assert!(article_downvote(&mut conn, "user3", &article, Some(3)).unwrap());
+        println!("After the third it's gone from the feed");
+        assert!(!in_feed(&mut conn));
+        let score: f64 = conn.zscore("score:", &article).unwrap();
+        assert_eq!(score, posted.score - 3.0 * VOTE_SCORE);
+
+        println!("Nor can someone who voted against it vote for it, until they take that back");
+        assert!(!article_vote(&mut conn, "user1", &article).unwrap());
+        let voted: bool = conn
+            .sismember(DefaultScheme.voted(&posted.id), "user1")
+            .unwrap();
+        assert!(!voted);
+        assert!(article_unvote(&mut conn, "user1", &article).unwrap());
+        let against: bool = conn
+            .sismember(DefaultScheme.against(&posted.id), "user1")
+            .unwrap();
+        assert!(!against);
+        let score: f64 = conn.zscore("score:", &article).unwrap();
+        assert_eq!(score, posted.score - 2.0 * VOTE_SCORE);
+        assert!(article_vote(&mut conn, "user1", &article).unwrap());
+
+        assert!(unhide_article(&mut conn, &article).unwrap());
+        assert!(!unhide_article(&mut conn, &article).unwrap());
+        assert!(in_feed(&mut conn));
+
+        delete_article(&mut conn, &posted.id);
+    }
+
+    #[test]
+    fn test_article_downvote_with_scheme() {
+        let local = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let mut fake = fake_clock(local + Duration::from_secs(8 * 86400));
+        let error = article_downvote_with_scheme(
+            &mut fake,
+            &DefaultScheme,
+            "username",
+            "article:0",
+            None,
+            ClockSource::Redis,
+        )
+        .unwrap_err();
+        println!("Downvoting by a clock 8 days ahead gives: {error}");
+        assert_eq!(
+            error.downcast_ref::<AppError>(),
+            Some(&AppError::TooOld("article:0".to_owned()))
+        );
+
+        let (_redis, client) = test_client();
+        let mut conn = client
+            .get_connection()
+            .expect("Should be able to Establish Connection");
+        let mut subscriber = client
+            .get_connection()
+            .expect("Should be able to Establish Connection");
+        let mut pubsub = subscriber.as_pubsub();
+        pubsub.subscribe(VOTE_CHANNEL).unwrap();
+        pubsub
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+
+        let ns = Namespace::new("downvote-scheme-test");
+        let article = ns.key("article:1");
+        conn.zadd::<_, _, _, usize>(ns.time(), &article, local.as_millis() as f64)
+            .unwrap();
+        conn.zadd::<_, _, _, usize>(ns.score(), &article, 0)
+            .unwrap();
+        println!(
+            "Let's downvote {article}, which lives under the {} namespace",
+            ns.0
+        );
+        let counted = article_downvote_with_scheme(
+            &mut conn,
+            &ns,
+            "scheme_voter",
+            &article,
+            Some(1),
+            ClockSource::Local,
+        )
+        .unwrap();
+        assert!(counted);
+        let against: bool = conn.sismember(ns.against("1"), "scheme_voter").unwrap();
+        let hidden: bool = conn.sismember(ns.key("hidden:"), &article).unwrap();
+        let score: f64 = conn.zscore(ns.score(), &article).unwrap();
+        assert!(against);
+        assert!(hidden);
+        assert_eq!(score, -VOTE_SCORE);
+
+        let mut notifications = vec![];
+        while let Ok(message) = pubsub.get_message() {
+            let payload: serde_json::Value =
+                serde_json::from_str(&message.get_payload::<String>().unwrap()).unwrap();
+            if payload["article"] == article.as_str() {
+                notifications.push(payload);
+            }
+        }
+        println!("And it was announced as: {notifications:?}");
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0]["direction"], "down");
+        #[cfg(feature = "vote-log")]
+        {
+            let events = read_vote_events(&mut conn, &ns, 10).unwrap();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].direction, "down");
+        }
+
+        conn.del::<_, usize>(&[
+            ns.against("1"),
+            ns.time(),
+            ns.score(),
+            ns.key("hidden:"),
+            ns.key("votes:stream"),
+        ])
+        .unwrap();
+    }
+
+    #[test]
+    fn test_get_articles_with_direction() {
+        let (_redis, mut conn) = test_conn();
+
+        let mut posted = vec![];
+        for _ in 0..3 {
+            let article =
+                post_article_full(&mut conn, "username", "A title", "http://google.com").unwrap();
+            let mut key = "article:".to_owned();
+            key.push_str(&article.id);
+            posted.push(key);
+        }
+        // A ranking of just these three, so other articles can't get in between
+        let ranking = "direction-test:";
+        for (score, key) in posted.iter().enumerate() {
+            conn.zadd::<_, _, _, usize>(ranking, key, score).unwrap();
+        }
+
+        let ids = |articles: Vec<Article>| {
+            articles
+                .into_iter()
+                .map(|article| article.id)
+                .collect::<Vec<_>>()
+        };
+        let desc = ids(get_articles_with_direction(
+            &mut conn,
+            1,
+            Some(ranking.to_owned()),
+            SortDirection::Desc,
+        )
+        .unwrap());
+        let mut asc = ids(get_articles_with_direction(
+            &mut conn,
+            1,
+            Some(ranking.to_owned()),
+            SortDirection::Asc,
+        )
+        .unwrap());
+        println!("Descending: {desc:?}, ascending: {asc:?}");
+        assert_eq!(desc.len(), 3);
+        assert_eq!(asc[0], posted[0].trim_start_matches("article:"));
+        asc.reverse();
+        assert_eq!(asc, desc);
+        let past_the_end =
+            get_articles_with_direction(&mut conn, 2, Some(ranking.to_owned()), SortDirection::Asc)
+                .unwrap();
+        assert!(past_the_end.is_empty());
+
+        conn.del::<_, usize>(ranking).unwrap();
+        for key in &posted {
+            delete_article(&mut conn, key.trim_start_matches("article:"));
+        }
+    }
+
+    #[test]
+    fn test_post_article_with_extra() {
+        let (_redis, mut conn) = test_conn();
+
+        let extra = [
+            ("category", "news"),
+            ("thumbnail", "http://google.com/a.png"),
+        ];
+        let posted = post_article_with_extra(
+            &mut conn,
+            "username",
+            "A title",
+            "http://google.com",
+            &extra,
+        )
+        .unwrap();
+        println!("Posting with extra fields gave: {posted:?}");
+        assert_eq!(posted.extra["category"], "news");
+
+        let stored = get_article(&mut conn, &posted.id).unwrap().unwrap();
+        assert_eq!(stored, posted);
+        assert_eq!(stored.extra.len(), 2);
+        assert_eq!(stored.title, "A title");
+
+        println!("Extra fields can't overwrite the article's own");
+        let error = post_article_with_extra(
+            &mut conn,
+            "username",
+            "A title",
+            "http://google.com",
+            &[("votes", "1000")],
+        )
+        .unwrap_err();
+        println!("Trying gives: {error}");
+        assert!(matches!(
+            error.downcast_ref::<AppError>(),
+            Some(AppError::BadInput(_))
+        ));
+
+        delete_article(&mut conn, &posted.id);
+    }
+
+    #[test]
+    fn test_article_vote_in_group() {
+        let (_redis, mut conn) = test_conn();
+        let group = "vote-group";
+
+        let first = post_article_tagged(
+            &mut conn,
+            "username",
+            "A title",
+            "http://google.com",
+            &[group],
+        )
+        .unwrap();
+        let second = post_article_tagged(
+            &mut conn,
+            "username",
+            "Another title",
+            "http://google.com",
+            &[group],
+        )
+        .unwrap();
+        let mut article = "article:".to_owned();
+        article.push_str(&first.id);
+
+        println!("Voting in the group counts towards the global and the group's scores");
+        assert!(article_vote_in_group(&mut conn, "other_user", &article, group).unwrap());
+        let stored = get_article(&mut conn, &first.id).unwrap().unwrap();
+        assert_eq!(stored.votes, 2);
+        assert_eq!(stored.score, first.score + VOTE_SCORE);
+        let group_score: f64 = conn.zscore("group_score:vote-group", &article).unwrap();
+        assert_eq!(group_score, 1.0);
+
+        println!("But only once per user");
+        assert!(!article_vote_in_group(&mut conn, "other_user", &article, group).unwrap());
+        let group_score: f64 = conn.zscore("group_score:vote-group", &article).unwrap();
+        assert_eq!(group_score, 1.0);
+
+        println!("The poster already voted globally, but can still vote in the group");
+        assert!(article_vote_in_group(&mut conn, "username", &article, group).unwrap());
+        let stored = get_article(&mut conn, &first.id).unwrap().unwrap();
+        assert_eq!(stored.votes, 2);
+        let group_score: f64 = conn.zscore("group_score:vote-group", &article).unwrap();
+        assert_eq!(group_score, 2.0);
+
+        let ranked = get_group_articles_by_group_votes(&mut conn, group, 1).unwrap();
+        let ids = ranked
+            .iter()
+            .map(|article| article.id.as_str())
+            .collect::<Vec<_>>();
+        println!("Ranked by group votes, the group is {ids:?}");
+        assert_eq!(ids, [first.id.as_str(), second.id.as_str()]);
+
+        let error =
+            article_vote_in_group(&mut conn, "other_user", &article, "other-group").unwrap_err();
+        println!("Voting in a group the article isn't in gives: {error}");
+        assert!(matches!(
+            error.downcast_ref::<AppError>(),
+            Some(AppError::BadInput(_))
+        ));
+
+        println!("A user who voted against an article can't vote for it in the group either");
+        let mut other = "article:".to_owned();
+        other.push_str(&second.id);
+        assert!(article_downvote(&mut conn, "critic", &other, None).unwrap());
+        assert!(!article_vote_in_group(&mut conn, "critic", &other, group).unwrap());
+        let group_score: Option<f64> = conn.zscore("group_score:vote-group", &other).unwrap();
+        assert_eq!(group_score, None);
+
+        println!("Leaving the group takes its group votes with it");
+        add_remove_groups(&mut conn, &first.id, vec![], vec![group]).unwrap();
+        let group_score: Option<f64> = conn.zscore("group_score:vote-group", &article).unwrap();
+        assert_eq!(group_score, None);
+        let mut group_voted = "group_voted:vote-group:".to_owned();
+        group_voted.push_str(&first.id);
+        let voted: bool = conn.exists(&group_voted).unwrap();
+        assert!(!voted);
+
+        delete_article(&mut conn, &first.id);
+        delete_article(&mut conn, &second.id);
+        conn.del::<_, usize>(&[
+            "group:vote-group",
+            "group_score:vote-group",
+            group_cache_key(GROUP_SCORE, group).as_str(),
+            "groupcache_index:vote-group",
+        ])
+        .unwrap();
+        conn.srem::<_, _, usize>("groups:", group).unwrap();
+    }
+
+    #[test]
+    fn test_get_articles_page() {
+        let (_redis, mut conn) = test_conn();
+        let article_id = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+
+        let first = get_articles_page(&mut conn, 1, None).unwrap();
+        println!(
+            "There are {} articles, and the first page has {} of them",
+            first.total,
+            first.items.len()
+        );
+        assert!(first.total >= 1);
+        assert!(!first.items.is_empty());
+        assert!(!first.out_of_range);
+
+        let last = (first.total as isize + ARTICLES_PER_PAGE - 1) / ARTICLES_PER_PAGE;
+        let past = get_articles_page(&mut conn, last + 1, None).unwrap();
+        println!("Page {} is past the end: {past:?}", last + 1);
+        assert!(past.items.is_empty());
+        assert!(past.out_of_range);
+        assert!(!past.has_next);
+        assert!(
+            !get_articles_page(&mut conn, last, None)
+                .unwrap()
+                .out_of_range
+        );
+
+        println!("Page 0 is out of range too, rather than the last page over again");
+        let before = get_articles_page(&mut conn, 0, None).unwrap();
+        assert!(before.items.is_empty());
+        assert!(before.out_of_range);
+
+        println!("The first page of an empty listing is empty, but not out of range");
+        let empty = get_articles_page(&mut conn, 1, Some("nothing-here:".to_owned())).unwrap();
+        assert_eq!(empty.total, 0);
+        assert!(empty.items.is_empty());
+        assert!(!empty.out_of_range);
+        assert!(
+            get_articles_page(&mut conn, 2, Some("nothing-here:".to_owned()))
+                .unwrap()
+                .out_of_range
+        );
+
+        delete_article(&mut conn, &article_id);
+    }
+
+    #[test]
+    fn test_post_article_scored() {
+        let (_redis, mut conn) = test_conn();
+
+        let posted =
+            post_article_scored(&mut conn, "username", "A title", "http://google.com").unwrap();
+        println!("Posting an article gave {posted:?}");
+        assert_eq!(posted.score, posted.time as f64 + VOTE_SCORE);
+
+        let mut article = "article:".to_owned();
+        article.push_str(&posted.id.to_string());
+        let score: f64 = conn.zscore("score:", &article).unwrap();
+        assert_eq!(posted.score, score);
+        let time: f64 = conn.zscore("time:", &article).unwrap();
+        assert_eq!(posted.time as f64, time);
+
+        delete_article(&mut conn, &posted.id.to_string());
+    }
+
+    #[test]
+    fn test_small_group_ranking() {
+        let (_redis, mut conn) = test_conn();
+        let group = "small-group";
+
+        let mut ids = vec![];
+        for votes in [1, 3, 1, 2, 1] {
+            let posted = post_article_tagged(
+                &mut conn,
+                "username",
+                "A title",
+                "http://google.com",
+                &[group],
+            )
+            .unwrap();
+            let mut article = "article:".to_owned();
+            article.push_str(&posted.id);
+            for voter in 1..votes {
+                article_vote(&mut conn, format!("voter{voter}").as_str(), &article).unwrap();
             }
+            ids.push(posted.id);
+        }
+
+        for order in ["score:", "time:", "votes:"] {
+            let order: ArticleOrder = order.parse().unwrap();
+            let key = group_cache_key(order.key(), group);
+            let in_process =
+                get_group_articles_ex(&mut conn, group, 1, Some(order.key().to_owned()), true)
+                    .unwrap();
+            assert!(!in_process.cached);
+
+            conn.del::<_, usize>(&key).unwrap();
+            intersect_group(&mut conn, group, &key, order).unwrap();
+            let intersected = get_articles(&mut conn, 1, Some(key)).unwrap();
+
+            let ranking = |articles: &[Article]| {
+                articles
+                    .iter()
+                    .map(|article| article.id.clone())
+                    .collect::<Vec<_>>()
+            };
+            println!(
+                "By {}, the group ranks as {:?}",
+                order.key(),
+                ranking(&in_process.articles)
+            );
+            assert_eq!(in_process.articles.len(), ids.len());
+            assert_eq!(ranking(&in_process.articles), ranking(&intersected));
+        }
+
+        for id in &ids {
+            delete_article(&mut conn, id);
         }
+        let cached: Vec<String> = conn.keys("groupcache*:small-group").unwrap();
+        conn.del::<_, usize>(cached).unwrap();
+        conn.del::<_, usize>("group:small-group").unwrap();
+        conn.srem::<_, _, usize>("groups:", group).unwrap();
+    }
+
+    #[test]
+    fn test_article_missing_score() {
+        let (_redis, mut conn) = test_conn();
+
+        let article_id = post_article(&mut conn, "username", "A title", "http://google.com")
+            .unwrap()
+            .to_string();
+        let mut article = "article:".to_owned();
+        article.push_str(&article_id);
+        conn.zrem::<_, _, usize>("score:", &article).unwrap();
+
+        println!("An article that's dropped out of score: can still be looked up");
+        let fetched = get_article(&mut conn, &article_id).unwrap().unwrap();
+        assert_eq!(fetched.score, 0.0);
+        let mut order = "test-order:".to_owned();
+        order.push_str(&article_id);
+        conn.zadd::<_, _, _, usize>(&order, &article, 1).unwrap();
+        let listed = get_articles(&mut conn, 1, Some(order.clone())).unwrap();
+        assert_eq!(listed, vec![fetched]);
+
+        conn.del::<_, usize>(&order).unwrap();
+        delete_article(&mut conn, &article_id);
     }
 }
@@ -0,0 +1,47 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+
+use redis::{Connection, ErrorKind, RedisError, RedisResult};
+
+// A fixed set of connections that are opened once and then shared, rather than each caller opening
+// (and tearing down) a connection of its own. Callers are handed the connections in turn, and only
+// hold one for as long as their call to `with_conn`, so the pool is for many short calls (i.e. one
+// pass of a worker's loop) rather than for a thread to keep a connection to itself.
+pub struct ConnPool {
+    conns: Vec<Mutex<Connection>>,
+    next: AtomicUsize,
+}
+
+impl ConnPool {
+    // Opens all `size` connections up front, so that an unreachable server is reported here. A pool
+    // has to have at least one connection.
+    pub fn new(client: &redis::Client, size: usize) -> RedisResult<ConnPool> {
+        if size == 0 {
+            return Err(RedisError::from((
+                ErrorKind::InvalidClientConfig,
+                "A connection pool needs at least one connection",
+            )));
+        }
+        let conns = (0..size)
+            .map(|_| client.get_connection().map(Mutex::new))
+            .collect::<RedisResult<Vec<_>>>()?;
+        Ok(ConnPool {
+            conns,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    // Runs `f` with the next connection in the pool, waiting for it if another caller is still using
+    // it
+    pub fn with_conn<T>(&self, f: impl FnOnce(&mut Connection) -> T) -> T {
+        let slot = self.next.fetch_add(1, Ordering::Relaxed) % self.conns.len();
+        // A caller that panicked while using a connection doesn't leave it in a broken state as far
+        // as Redis is concerned, so the connection is still handed out
+        let mut conn = self.conns[slot]
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(&mut conn)
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use redis::{Commands, ConnectionLike};
+
+const ONE_HOUR_IN_SECONDS: u64 = 3600;
+
+// Moves the previous hour's statistics (KEYS[1]) and start time (KEYS[3]) to `:last` and `:pstart`,
+// then starts the current hour at ARGV[1]. An hour can pass without any samples, so there may be no
+// statistics to move, in which case a RENAME would fail the whole transaction; the stale `:last` is
+// dropped instead.
+const ROLLOVER_SCRIPT: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    redis.call('RENAME', KEYS[1], KEYS[2])
+else
+    redis.call('DEL', KEYS[2])
+end
+redis.call('RENAME', KEYS[3], KEYS[4])
+redis.call('SET', KEYS[3], ARGV[1])
+";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StatSummary {
+    pub count: f64,
+    pub sum: f64,
+    pub sumsq: f64,
+    pub min: f64,
+    pub max: f64,
+    pub average: f64,
+    pub stddev: f64,
+}
+
+// Adds `value` to the running statistics for a context/type pair (i.e. "ProfilePage"/"AccessTime"),
+// returning the (count, mean, standard deviation) of the samples so far. Statistics are kept per
+// hour; the first sample of a new hour moves the previous hour's statistics to `<key>:last`. Needs
+// Redis 6.2 or newer, for ZADD's LT and GT flags.
+pub fn update_stats(
+    conn: &mut impl ConnectionLike,
+    context: &str,
+    stat_type: &str,
+    value: f64,
+    now: SystemTime,
+) -> Result<(f64, f64, f64), Box<dyn Error>> {
+    let destination = stats_key(context, stat_type);
+    let mut start_key = destination.clone();
+    start_key.push_str(":start");
+    let mut last = destination.clone();
+    last.push_str(":last");
+    let mut previous_start = destination.clone();
+    previous_start.push_str(":pstart");
+
+    let now = now.duration_since(UNIX_EPOCH)?.as_secs();
+    let hour_start = now - now % ONE_HOUR_IN_SECONDS;
+
+    // The start of the current hour is WATCHed so that if another client rolls the statistics over
+    // to a new hour partway through, the update is retried against the new hour instead.
+    let (count, sum, sumsq): (f64, f64, f64) =
+        redis::transaction(conn, &[&start_key], |conn, pipe| {
+            let existing: Option<u64> = conn.get(&start_key)?;
+            match existing {
+                Some(existing) if existing < hour_start => {
+                    pipe.cmd("EVAL")
+                        .arg(ROLLOVER_SCRIPT)
+                        .arg(4)
+                        .arg(&destination)
+                        .arg(&last)
+                        .arg(&start_key)
+                        .arg(&previous_start)
+                        .arg(hour_start)
+                        .ignore();
+                }
+                None => {
+                    pipe.set(&start_key, hour_start).ignore();
+                }
+                _ => {}
+            }
+
+            // ZADD's LT and GT flags (new in Redis 6.2) only replace an existing score if the new one
+            // is lower/higher, which saves going through temporary ZSETs and ZUNIONSTORE's MIN/MAX
+            // aggregates.
+            pipe.cmd("ZADD")
+                .arg(&destination)
+                .arg("LT")
+                .arg(value)
+                .arg("min")
+                .ignore()
+                .cmd("ZADD")
+                .arg(&destination)
+                .arg("GT")
+                .arg(value)
+                .arg("max")
+                .ignore()
+                .zincr(&destination, "count", 1)
+                .zincr(&destination, "sum", value)
+                .zincr(&destination, "sumsq", value * value)
+                .query(conn)
+        })?;
+
+    Ok((count, sum / count, stddev(count, sum, sumsq)))
+}
+
+pub fn get_stats(
+    conn: &mut impl ConnectionLike,
+    context: &str,
+    stat_type: &str,
+) -> Result<StatSummary, Box<dyn Error>> {
+    let values: HashMap<String, f64> =
+        conn.zrange_withscores(stats_key(context, stat_type), 0, -1)?;
+    let value = |name: &str| values.get(name).copied().unwrap_or_default();
+
+    let (count, sum, sumsq) = (value("count"), value("sum"), value("sumsq"));
+    Ok(StatSummary {
+        count,
+        sum,
+        sumsq,
+        min: value("min"),
+        max: value("max"),
+        average: if count > 0.0 { sum / count } else { 0.0 },
+        stddev: stddev(count, sum, sumsq),
+    })
+}
+
+// Turns an IP address into the score it's looked up by in the IP-to-location ZSET, by treating its
+// octets as the digits of a base 256 number. Only IPv4 is supported for now, but the score is a u128
+// so that it's already wide enough for IPv6 addresses; they're rejected with an error rather than
+// being mangled into a score that would collide with an IPv4 one.
+pub fn ip_to_score(ip_address: &str) -> Result<u128, Box<dyn Error>> {
+    match ip_address.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => Ok(ip
+            .octets()
+            .iter()
+            .fold(0, |score, &octet| score * 256 + octet as u128)),
+        Ok(IpAddr::V6(_)) => {
+            Err(format!("Looking up IPv6 addresses isn't supported yet: {ip_address}").into())
+        }
+        Err(e) => Err(format!("{ip_address} isn't a valid IP address: {e}").into()),
+    }
+}
+
+fn stats_key(context: &str, stat_type: &str) -> String {
+    let mut key = "stats:".to_owned();
+    key.push_str(context);
+    key.push(':');
+    key.push_str(stat_type);
+    key
+}
+
+// The sample standard deviation, worked out from just the count, sum and sum of squares
+fn stddev(count: f64, sum: f64, sumsq: f64) -> f64 {
+    if count <= 1.0 {
+        return 0.0;
+    }
+    ((sumsq - sum * sum / count) / (count - 1.0)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use redis::Commands;
+
+    use crate::{get_stats, ip_to_score, update_stats};
+
+    test_support::test_connections!();
+
+    // Execute`cargo test -p ch05 -- --nocapture --test-threads 1` to run these tests
+    // specifying 1 test thread means one test runs at a time so things run sequentially. With
+    // `--features test-containers` every test has a Redis to itself, so that's no longer needed.
+    #[test]
+    fn test_stats() {
+        let (_redis, mut conn) = test_conn();
+
+        let keys = [
+            "stats:test:AccessTime",
+            "stats:test:AccessTime:start",
+            "stats:test:AccessTime:last",
+            "stats:test:AccessTime:pstart",
+        ];
+        conn.del::<_, usize>(&keys).unwrap();
+
+        println!("Let's add a few access time samples");
+        let samples = [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0];
+        let mut result = (0.0, 0.0, 0.0);
+        for sample in samples {
+            result = update_stats(&mut conn, "test", "AccessTime", sample, SystemTime::now())
+                .expect("Stats should update");
+        }
+        println!(
+            "After {} samples, (count, mean, stddev) is {result:?}",
+            samples.len()
+        );
+        let (count, mean, stddev) = result;
+        assert_eq!(count, 8.0);
+        assert!((mean - 5.0).abs() < 1e-9);
+        // The sample standard deviation of the above is sqrt(32 / 7)
+        assert!((stddev - (32.0_f64 / 7.0).sqrt()).abs() < 1e-9);
+
+        let stats = get_stats(&mut conn, "test", "AccessTime").expect("Stats should be readable");
+        println!("The full summary looks like: {stats:#?}");
+        assert_eq!(stats.min, 2.0);
+        assert_eq!(stats.max, 9.0);
+        assert!((stats.average - mean).abs() < 1e-9);
+        assert!((stats.stddev - stddev).abs() < 1e-9);
+
+        conn.del::<_, usize>(&keys).unwrap();
+    }
+
+    #[test]
+    fn test_ip_to_score() {
+        assert_eq!(ip_to_score("0.0.0.0").unwrap(), 0);
+        assert_eq!(ip_to_score("1.2.3.4").unwrap(), 16909060);
+        assert_eq!(ip_to_score("255.255.255.255").unwrap(), u32::MAX as u128);
+
+        let out_of_range = ip_to_score("1.2.3.256").unwrap_err();
+        println!("An out of range octet gives: {out_of_range}");
+        assert!(out_of_range
+            .to_string()
+            .contains("isn't a valid IP address"));
+
+        let ipv6 = ip_to_score("2001:db8::1").unwrap_err();
+        println!("An IPv6 address gives: {ipv6}");
+        assert!(ipv6.to_string().contains("IPv6"));
+    }
+
+    #[test]
+    fn test_stats_rollover() {
+        let (_redis, mut conn) = test_conn();
+
+        let keys = [
+            "stats:rollover:AccessTime",
+            "stats:rollover:AccessTime:start",
+            "stats:rollover:AccessTime:last",
+            "stats:rollover:AccessTime:pstart",
+        ];
+        conn.del::<_, usize>(&keys).unwrap();
+        let now = SystemTime::now();
+        let an_hour_ago = now - Duration::from_secs(3600);
+
+        println!("A sample from an hour ago, then one now, moves the old hour to :last");
+        update_stats(&mut conn, "rollover", "AccessTime", 2.0, an_hour_ago).unwrap();
+        let (count, mean, _) = update_stats(&mut conn, "rollover", "AccessTime", 4.0, now).unwrap();
+        assert_eq!(count, 1.0);
+        assert_eq!(mean, 4.0);
+        let last: Option<f64> = conn.zscore(keys[2], "sum").unwrap();
+        assert_eq!(last, Some(2.0));
+
+        println!("If an hour's statistics are gone by the time it rolls over, that's not an error");
+        conn.del::<_, usize>(keys[0]).unwrap();
+        conn.set::<_, _, ()>(keys[1], 0).unwrap();
+        let (count, _, _) = update_stats(&mut conn, "rollover", "AccessTime", 6.0, now).unwrap();
+        assert_eq!(count, 1.0);
+        let last: Option<f64> = conn.zscore(keys[2], "sum").unwrap();
+        assert_eq!(last, None);
+
+        conn.del::<_, usize>(&keys).unwrap();
+    }
+}
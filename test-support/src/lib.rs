@@ -0,0 +1,182 @@
+#[cfg(feature = "test-containers")]
+use std::sync::OnceLock;
+
+#[cfg(feature = "test-containers")]
+use testcontainers::{clients::Cli, core::WaitFor, images::generic::GenericImage, Container};
+
+// With the `test-containers` feature each test gets a Redis of its own, running in a throwaway Docker
+// container that's removed when the returned guard is dropped, even if the test panicked. Without it,
+// tests share the server at redis://127.0.0.1.
+#[cfg(feature = "test-containers")]
+pub type TestRedis = Container<'static, GenericImage>;
+#[cfg(not(feature = "test-containers"))]
+pub type TestRedis = ();
+
+// Starts a Redis for a test, returning the guard that keeps it running along with its URL
+#[cfg(feature = "test-containers")]
+pub fn test_redis() -> (TestRedis, String) {
+    // A container borrows the Docker client that started it, so every test shares one that lives for
+    // as long as the tests do
+    static DOCKER: OnceLock<Cli> = OnceLock::new();
+    let image = GenericImage::new("redis", "7")
+        .with_exposed_port(6379)
+        .with_wait_for(WaitFor::message_on_stdout("Ready to accept connections"));
+    let container = DOCKER.get_or_init(Cli::default).run(image);
+    let url = format!("redis://127.0.0.1:{}", container.get_host_port_ipv4(6379));
+    (container, url)
+}
+
+#[cfg(not(feature = "test-containers"))]
+pub fn test_redis() -> (TestRedis, String) {
+    ((), "redis://127.0.0.1".to_owned())
+}
+
+// Defines `test_client` and `test_conn`, which open a client to (or a connection to) the Redis from
+// `test_redis`, along with the guard that keeps it running. Like `fake_connection`, it's a macro so
+// that they return the types of whichever `redis` the calling crate uses.
+#[macro_export]
+macro_rules! test_connections {
+    () => {
+        #[allow(dead_code)]
+        fn test_client() -> ($crate::TestRedis, redis::Client) {
+            let (guard, url) = $crate::test_redis();
+            let client =
+                redis::Client::open(url.as_str()).expect("Should be able to reach Redis Server");
+            (guard, client)
+        }
+
+        #[allow(dead_code)]
+        fn test_conn() -> ($crate::TestRedis, redis::Connection) {
+            let (guard, client) = test_client();
+            let conn = client
+                .get_connection()
+                .expect("Should be able to Establish Connection");
+            (guard, conn)
+        }
+    };
+}
+
+// Splits what redis-rs packed for one round-trip back into its commands, each as its arguments with
+// the command's name first. Commands are packed as `*<argc>\r\n$<len>\r\n<arg>\r\n...`, one after
+// another for a pipeline.
+pub fn unpack_commands(mut packed: &[u8]) -> Vec<Vec<String>> {
+    fn read_number(bytes: &[u8]) -> (usize, &[u8]) {
+        let end = bytes.iter().position(|&b| b == b'\r').unwrap();
+        let number = String::from_utf8_lossy(&bytes[1..end]).parse().unwrap();
+        (number, &bytes[end + 2..])
+    }
+
+    let mut commands = vec![];
+    while !packed.is_empty() {
+        let (argc, mut rest) = read_number(packed);
+        let mut args = vec![];
+        for _ in 0..argc {
+            let (len, arg) = read_number(rest);
+            args.push(String::from_utf8_lossy(&arg[..len]).into_owned());
+            rest = &arg[len + 2..];
+        }
+        commands.push(args);
+        packed = rest;
+    }
+    commands
+}
+
+// Defines `FakeConnection`, which stands in for Redis in tests that only care about which commands
+// get sent. Each command is answered by the closure it's made with, which is handed the command's
+// arguments with its (upper-cased) name first. Pipelines and MULTI/EXEC are answered the way Redis
+// would. It's a macro rather than a type so that it implements the `ConnectionLike` of whichever
+// `redis` the calling crate uses.
+#[macro_export]
+macro_rules! fake_connection {
+    () => {
+        struct FakeConnection<R> {
+            // The names of the commands sent so far, upper-cased
+            commands: Vec<String>,
+            replies: R,
+        }
+
+        impl<R: FnMut(&[String]) -> redis::Value> FakeConnection<R> {
+            fn new(replies: R) -> FakeConnection<R> {
+                FakeConnection {
+                    commands: vec![],
+                    replies,
+                }
+            }
+
+            fn answer(&mut self, packed: &[u8]) -> Vec<redis::Value> {
+                let mut answers = vec![];
+                // The replies to the commands queued since a MULTI, which its EXEC replies with
+                let mut queued: Option<Vec<redis::Value>> = None;
+                for mut command in $crate::unpack_commands(packed) {
+                    command[0] = command[0].to_uppercase();
+                    let answer = match command[0].as_str() {
+                        "MULTI" => {
+                            queued = Some(vec![]);
+                            redis::Value::Okay
+                        }
+                        "EXEC" => redis::Value::Bulk(queued.take().unwrap_or_default()),
+                        _ => {
+                            let reply = (self.replies)(&command);
+                            match queued.as_mut() {
+                                Some(queued) => {
+                                    queued.push(reply);
+                                    redis::Value::Status("QUEUED".to_owned())
+                                }
+                                None => reply,
+                            }
+                        }
+                    };
+                    self.commands.push(command.swap_remove(0));
+                    answers.push(answer);
+                }
+                answers
+            }
+        }
+
+        impl<R: FnMut(&[String]) -> redis::Value> redis::ConnectionLike for FakeConnection<R> {
+            fn req_packed_command(&mut self, cmd: &[u8]) -> redis::RedisResult<redis::Value> {
+                Ok(self.answer(cmd).remove(0))
+            }
+
+            fn req_packed_commands(
+                &mut self,
+                cmd: &[u8],
+                offset: usize,
+                count: usize,
+            ) -> redis::RedisResult<Vec<redis::Value>> {
+                let answers = self.answer(cmd);
+                Ok(answers.into_iter().skip(offset).take(count).collect())
+            }
+
+            fn get_db(&self) -> i64 {
+                0
+            }
+
+            fn check_connection(&mut self) -> bool {
+                true
+            }
+
+            fn is_open(&self) -> bool {
+                true
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::unpack_commands;
+
+    #[test]
+    fn test_unpack_commands() {
+        let packed =
+            b"*2\r\n$3\r\nGET\r\n$4\r\nkey:\r\n*3\r\n$4\r\nZADD\r\n$1\r\n1\r\n$6\r\na\r\nb c\r\n";
+        let commands = unpack_commands(packed);
+        println!("We unpacked {commands:?}");
+        assert_eq!(
+            commands,
+            [vec!["GET", "key:"], vec!["ZADD", "1", "a\r\nb c"]]
+        );
+        assert!(unpack_commands(b"").is_empty());
+    }
+}